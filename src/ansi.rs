@@ -0,0 +1,144 @@
+use std::iter::Peekable;
+use std::str::Chars;
+use tui::{
+    style::{Color, Modifier, Style},
+    text::{Span, Spans, Text},
+};
+
+/// Turns command output that may contain ANSI escape sequences into styled
+/// text. Colours and text attributes set through SGR sequences are kept,
+/// any other escape sequence is dropped. A carriage return starts its line
+/// over, so progress bars only show their latest state.
+pub fn to_text(output: &str) -> Text<'static> {
+    let mut lines = vec![];
+    let mut line = vec![];
+    let mut current = String::new();
+    let mut style = Style::default();
+
+    let mut chars = output.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                flush(&mut line, &mut current, style);
+                lines.push(Spans::from(std::mem::take(&mut line)));
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\r' => {
+                line.clear();
+                current.clear();
+            }
+            '\x08' => {
+                current.pop();
+            }
+            '\x1b' => match chars.next() {
+                Some('[') => {
+                    let (params, command) = control_sequence(&mut chars);
+                    if command == Some('m') {
+                        flush(&mut line, &mut current, style);
+                        style = select_graphic_rendition(style, &params);
+                    }
+                }
+                // operating system commands such as window titles end at BEL
+                // or at ESC \
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\t' => current.push_str("    "),
+            c if c.is_control() => {}
+            c => current.push(c),
+        }
+    }
+    flush(&mut line, &mut current, style);
+    if !line.is_empty() {
+        lines.push(Spans::from(line));
+    }
+    Text::from(lines)
+}
+
+fn flush(line: &mut Vec<Span<'static>>, current: &mut String, style: Style) {
+    if !current.is_empty() {
+        line.push(Span::styled(std::mem::take(current), style));
+    }
+}
+
+/// Reads the parameters and final character of a control sequence, the
+/// part following `ESC [`.
+fn control_sequence(chars: &mut Peekable<Chars>) -> (Vec<u16>, Option<char>) {
+    let mut params = String::new();
+    for c in chars.by_ref() {
+        if ('\x40'..='\x7e').contains(&c) {
+            let params = params.split(';').map(|param| param.parse().unwrap_or(0));
+            return (params.collect(), Some(c));
+        }
+        params.push(c);
+    }
+    (vec![], None)
+}
+
+fn select_graphic_rendition(mut style: Style, params: &[u16]) -> Style {
+    let mut params = params.iter().copied();
+    while let Some(param) = params.next() {
+        match param {
+            0 => style = Style::default(),
+            1 => style.add_modifier.insert(Modifier::BOLD),
+            2 => style.add_modifier.insert(Modifier::DIM),
+            3 => style.add_modifier.insert(Modifier::ITALIC),
+            4 => style.add_modifier.insert(Modifier::UNDERLINED),
+            7 => style.add_modifier.insert(Modifier::REVERSED),
+            22 => style.add_modifier.remove(Modifier::BOLD | Modifier::DIM),
+            23 => style.add_modifier.remove(Modifier::ITALIC),
+            24 => style.add_modifier.remove(Modifier::UNDERLINED),
+            27 => style.add_modifier.remove(Modifier::REVERSED),
+            30..=37 => style.fg = Some(indexed_color(param - 30)),
+            38 => style.fg = extended_color(&mut params),
+            39 => style.fg = None,
+            40..=47 => style.bg = Some(indexed_color(param - 40)),
+            48 => style.bg = extended_color(&mut params),
+            49 => style.bg = None,
+            90..=97 => style.fg = Some(indexed_color(param - 90 + 8)),
+            100..=107 => style.bg = Some(indexed_color(param - 100 + 8)),
+            _ => {}
+        }
+    }
+    style
+}
+
+/// Reads the rest of a `38;5;n` or `38;2;r;g;b` colour.
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::Indexed(params.next()? as u8)),
+        2 => Some(Color::Rgb(
+            params.next()? as u8,
+            params.next()? as u8,
+            params.next()? as u8,
+        )),
+        _ => None,
+    }
+}
+
+fn indexed_color(index: u16) -> Color {
+    match index {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        7 => Color::Gray,
+        8 => Color::DarkGray,
+        9 => Color::LightRed,
+        10 => Color::LightGreen,
+        11 => Color::LightYellow,
+        12 => Color::LightBlue,
+        13 => Color::LightMagenta,
+        14 => Color::LightCyan,
+        _ => Color::White,
+    }
+}
@@ -0,0 +1,281 @@
+use crate::daemon::{check_peer, Message, Request, TaskUpdate, Update};
+use crate::{Attempt, ParseError, Recurring, Task, TaskOutput, TaskSpec, Workers};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{net::UnixStream, process::ExitStatusExt},
+    path::Path,
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// A connection to the daemon that runs the tasks. The tasks are mirrored
+/// from the updates the daemon sends and read the same way as those of
+/// `Tasks`, every change to them is asked of the daemon.
+pub struct Client {
+    stream: Mutex<UnixStream>,
+    replies: Mutex<Receiver<Message>>,
+    connected: Arc<AtomicBool>,
+    mirror: Mirror,
+    /// Finished tasks whose output was asked for already.
+    output_loaded: Mutex<HashSet<usize>>,
+}
+
+/// Tasks as of the last update from the daemon.
+#[derive(Clone, Default)]
+struct Mirror {
+    workers: Arc<Mutex<Workers>>,
+    recurring: Arc<Mutex<BTreeMap<usize, Recurring>>>,
+    currently_running: Arc<Mutex<BTreeMap<usize, Task>>>,
+    finished: Arc<Mutex<BTreeMap<usize, Task>>>,
+}
+
+impl Client {
+    /// Connects to the daemon listening on the Unix socket at `path`, and
+    /// waits for the first update, which holds every task. Fails if the
+    /// daemon runs as another user, who would get to see every command.
+    pub fn connect(path: &Path) -> io::Result<Client> {
+        let stream = UnixStream::connect(path)?;
+        check_peer(&stream)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let Message::Update(update) = serde_json::from_str(&line)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected an update from the daemon",
+            ));
+        };
+        let mirror = Mirror::default();
+        mirror.apply(update);
+        let (reply, replies) = channel();
+        let connected = Arc::new(AtomicBool::new(true));
+
+        {
+            let connected = connected.clone();
+            let mirror = mirror.clone();
+            thread::spawn(move || {
+                for line in reader.lines() {
+                    let Ok(message) = line.and_then(|line| Ok(serde_json::from_str(&line)?)) else {
+                        break;
+                    };
+                    match message {
+                        Message::Update(update) => mirror.apply(update),
+                        message => {
+                            if reply.send(message).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                connected.store(false, Ordering::Relaxed);
+            });
+        }
+
+        Ok(Client {
+            stream: Mutex::new(stream),
+            replies: Mutex::new(replies),
+            connected,
+            mirror,
+            output_loaded: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Whether the daemon is still there. Once it is gone the tasks stay
+    /// as they were last seen, and asking for changes fails.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn get_currently_running(&self) -> Arc<Mutex<BTreeMap<usize, Task>>> {
+        self.mirror.currently_running.clone()
+    }
+
+    pub fn get_finished(&self) -> Arc<Mutex<BTreeMap<usize, Task>>> {
+        self.mirror.finished.clone()
+    }
+
+    pub fn get_recurring(&self) -> Arc<Mutex<BTreeMap<usize, Recurring>>> {
+        self.mirror.recurring.clone()
+    }
+
+    pub fn get_workers(&self) -> Workers {
+        *self.mirror.workers.lock().unwrap()
+    }
+
+    /// See `Tasks::submit`. The task shows up with the next update.
+    pub fn submit(&self, mut spec: TaskSpec) -> Result<usize, ParseError> {
+        let env = spec.inherited_env.take();
+        match self.request(&Request::Submit {
+            spec: Box::new(spec),
+            env,
+        }) {
+            Some(Message::Submitted { result }) => result,
+            _ => Err(ParseError::Disconnected),
+        }
+    }
+
+    pub fn cancel(&self, id: usize) -> bool {
+        self.done(&Request::Cancel { id })
+    }
+
+    pub fn toggle_pause(&self, id: usize) -> bool {
+        self.done(&Request::TogglePause { id })
+    }
+
+    pub fn set_priority(&self, id: usize, priority: i32) -> bool {
+        self.done(&Request::SetPriority { id, priority })
+    }
+
+    pub fn send_input(&self, id: usize, input: Vec<u8>) -> bool {
+        self.done(&Request::SendInput { id, input })
+    }
+
+    /// Asks for the output of a finished task, which the daemon leaves out
+    /// of the updates unless the task was seen running. It shows up with
+    /// the next update. Asks only once for each task.
+    pub fn load_output(&self, id: usize) {
+        if self.output_loaded.lock().unwrap().insert(id) {
+            self.done(&Request::LoadOutput { id });
+        }
+    }
+
+    pub fn remove_recurring(&self, id: usize) -> bool {
+        self.done(&Request::RemoveRecurring { id })
+    }
+
+    pub fn set_workers(&self, limit: usize) {
+        self.done(&Request::SetWorkers { limit });
+    }
+
+    pub fn set_group_limit(&self, name: &str, limit: usize) {
+        let name = name.to_string();
+        self.done(&Request::SetGroupLimit { name, limit });
+    }
+
+    /// Stops every task and then the daemon, waiting until it is done.
+    /// Returns false if some of the tasks could not be stopped, or if the
+    /// daemon could not be asked to.
+    pub fn shutdown(&self) -> bool {
+        self.done(&Request::Shutdown)
+    }
+
+    /// Sends a request that is answered with whether it did anything.
+    fn done(&self, request: &Request) -> bool {
+        matches!(self.request(request), Some(Message::Done { ok: true }))
+    }
+
+    /// Sends a request and waits for the reply, `None` if the daemon is
+    /// gone.
+    fn request(&self, request: &Request) -> Option<Message> {
+        let mut line = serde_json::to_vec(request).ok()?;
+        line.push(b'\n');
+        // replies come in the order the requests were sent in, holding on
+        // to the stream until the reply is in keeps them paired up
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&line).ok()?;
+        let reply = self.replies.lock().unwrap().recv().ok();
+        drop(stream);
+        reply
+    }
+}
+
+impl Mirror {
+    fn apply(&self, update: Update) {
+        if let Some(workers) = update.workers {
+            *self.workers.lock().unwrap() = workers;
+        }
+        if let Some(recurring) = update.recurring {
+            *self.recurring.lock().unwrap() = recurring;
+        }
+        for task in update.tasks {
+            self.apply_task(task);
+        }
+    }
+
+    fn apply_task(&self, update: TaskUpdate) {
+        let id = update.id;
+        let finished = update.state.as_ref().is_some_and(|state| state.finished);
+
+        let mut running = self.currently_running.lock().unwrap();
+        if let Some(task) = running.get_mut(&id) {
+            apply(task, update);
+            if finished {
+                let task = running.remove(&id).unwrap();
+                drop(running);
+                self.finished.lock().unwrap().insert(id, task);
+            }
+            return;
+        }
+        drop(running);
+
+        let mut finished_tasks = self.finished.lock().unwrap();
+        if let Some(task) = finished_tasks.get_mut(&id) {
+            apply(task, update);
+            return;
+        }
+        drop(finished_tasks);
+
+        // the first update of a task always has its state
+        let Some(state) = &update.state else {
+            return;
+        };
+        let mut task = Task::new(state.spec.clone());
+        apply(&mut task, update);
+        let list = if finished {
+            &self.finished
+        } else {
+            &self.currently_running
+        };
+        list.lock().unwrap().insert(id, task);
+    }
+}
+
+/// Brings a mirrored task up to date.
+fn apply(task: &mut Task, update: TaskUpdate) {
+    if let Some(state) = update.state {
+        task.spec = state.spec;
+        task.start_time = state.start_time;
+        task.finish_time = state.finish_time;
+        task.status = state.status;
+        task.exit_status = state.exit_status.map(ExitStatus::from_raw);
+        task.pid = state.pid;
+        task.cancel_requested = state.cancel_requested;
+        task.pause_requested = state.pause_requested;
+        task.paused_time = state.paused_time;
+        task.parent = state.parent;
+        task.pgid = state.pgid;
+        // input goes through the daemon, the sender only tells that the
+        // task takes any
+        task.accepts_input = state.accepts_input;
+    }
+    for attempt in update.attempts {
+        let mut output = TaskOutput::default();
+        if let Some(update) = attempt.output {
+            output.apply(update);
+        }
+        task.attempts.push(Attempt {
+            start_time: attempt.start_time,
+            finish_time: attempt.finish_time,
+            status: attempt.status,
+            exit_status: attempt.exit_status.map(ExitStatus::from_raw),
+            output,
+        });
+    }
+    for (attempt, output) in task.attempts.iter_mut().zip(update.attempt_outputs) {
+        if let Some(output) = output {
+            attempt.output.apply(output);
+        }
+    }
+    if let Some(output) = update.output {
+        task.output.lock().unwrap().apply(output);
+    }
+    if let Some(usage) = update.usage {
+        task.usage = usage;
+    }
+}
@@ -0,0 +1,542 @@
+use crate::output::{OutputUpdate, Position};
+use crate::{
+    CommandStatus, ParseError, Recurring, Task, TaskSpec, Tasks, Usage, Workers, STOP_GRACE_PERIOD,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::{self, File, Permissions},
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    net::Shutdown,
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd},
+        net::{UnixListener, UnixStream},
+        process::ExitStatusExt,
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, sleep},
+    time::Duration,
+};
+
+/// How often clients are sent what changed.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a shutdown waits for the tasks to stop, long enough for those
+/// that have to be killed after `STOP_GRACE_PERIOD`.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(STOP_GRACE_PERIOD.as_secs() * 2 + 1);
+
+/// Write end of the pipe through which the signal handler wakes up the
+/// thread that shuts the daemon down.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// What a client asks the daemon to do. Every request gets a reply, in
+/// the order they were sent in.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub(crate) enum Request {
+    Submit {
+        spec: Box<TaskSpec>,
+        /// `TaskSpec::inherited_env`, which the spec does not carry.
+        env: Option<Vec<(String, String)>>,
+    },
+    Cancel {
+        id: usize,
+    },
+    TogglePause {
+        id: usize,
+    },
+    SetPriority {
+        id: usize,
+        priority: i32,
+    },
+    SendInput {
+        id: usize,
+        #[serde(with = "base64_bytes")]
+        input: Vec<u8>,
+    },
+    /// Asks for the output of a task that had finished before the client
+    /// first saw it, which is left out until then.
+    LoadOutput {
+        id: usize,
+    },
+    RemoveRecurring {
+        id: usize,
+    },
+    SetWorkers {
+        limit: usize,
+    },
+    SetGroupLimit {
+        name: String,
+        limit: usize,
+    },
+    /// Stops every task, and then the daemon.
+    Shutdown,
+}
+
+/// What the daemon sends to a client, one JSON line each.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "message", rename_all = "snake_case")]
+pub(crate) enum Message {
+    Update(Update),
+    /// Reply to `Request::Submit`.
+    Submitted {
+        result: Result<usize, ParseError>,
+    },
+    /// Reply to every other request, whether it did anything.
+    Done {
+        ok: bool,
+    },
+}
+
+/// What changed since the previous update sent to a client. The first
+/// one holds everything.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Update {
+    pub(crate) workers: Option<Workers>,
+    /// Every recurring task, if any of them changed.
+    pub(crate) recurring: Option<BTreeMap<usize, Recurring>>,
+    pub(crate) tasks: Vec<TaskUpdate>,
+}
+
+/// Changes to a single task, running or finished.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TaskUpdate {
+    pub(crate) id: usize,
+    pub(crate) state: Option<TaskState>,
+    /// Attempts that ended since the previous update.
+    pub(crate) attempts: Vec<AttemptState>,
+    /// Output of every earlier attempt, once it was asked for.
+    pub(crate) attempt_outputs: Vec<Option<OutputUpdate>>,
+    pub(crate) output: Option<OutputUpdate>,
+    pub(crate) usage: Option<VecDeque<Usage>>,
+}
+
+/// Everything about a task that is not sent along in pieces.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TaskState {
+    pub(crate) spec: TaskSpec,
+    pub(crate) start_time: DateTime<Local>,
+    pub(crate) finish_time: Option<DateTime<Local>>,
+    pub(crate) status: CommandStatus,
+    /// Raw wait status of the process, if there was one.
+    pub(crate) exit_status: Option<i32>,
+    pub(crate) pid: Option<u32>,
+    pub(crate) cancel_requested: bool,
+    pub(crate) pause_requested: bool,
+    pub(crate) paused_time: Duration,
+    pub(crate) parent: Option<usize>,
+    pub(crate) pgid: Option<u32>,
+    /// Whether the task takes input, which is sent through the daemon.
+    pub(crate) accepts_input: bool,
+    /// Set once the task moved to the finished ones.
+    pub(crate) finished: bool,
+}
+
+impl TaskState {
+    fn new(task: &Task, finished: bool) -> Self {
+        TaskState {
+            spec: task.spec.clone(),
+            start_time: task.start_time,
+            finish_time: task.finish_time,
+            status: task.status.clone(),
+            exit_status: task.exit_status.map(|status| status.into_raw()),
+            pid: task.pid,
+            cancel_requested: task.cancel_requested,
+            pause_requested: task.pause_requested,
+            paused_time: task.paused_time,
+            parent: task.parent,
+            pgid: task.pgid,
+            accepts_input: task.accepts_input,
+            finished,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AttemptState {
+    pub(crate) start_time: DateTime<Local>,
+    pub(crate) finish_time: DateTime<Local>,
+    pub(crate) status: CommandStatus,
+    pub(crate) exit_status: Option<i32>,
+    pub(crate) output: Option<OutputUpdate>,
+}
+
+/// What a client was sent so far, so that it is only sent what changed.
+#[derive(Default)]
+struct Sent {
+    workers: Option<Workers>,
+    /// `Tasks::recurring_version` when the recurring tasks were last sent.
+    recurring: Option<usize>,
+    tasks: HashMap<usize, SentTask>,
+}
+
+#[derive(Default)]
+struct SentTask {
+    /// `Task::version` when the state was last sent.
+    version: Option<usize>,
+    attempts: usize,
+    /// Position of the output of the current attempt, `None` until any of
+    /// it was sent.
+    output: Option<[Position; 2]>,
+    usage: VecDeque<Usage>,
+    /// Finished tasks do not change any more once they were sent.
+    finished: bool,
+    /// Whether the output was sent. That of a task that had finished
+    /// before the client first saw it is only sent once asked for, so that
+    /// connecting does not mean getting the output of every task there is.
+    output_sent: bool,
+}
+
+impl Sent {
+    /// What changed since the previous call, `None` if nothing did. The
+    /// output of the finished tasks in `load` is sent along if it was not
+    /// yet.
+    fn update(&mut self, tasks: &Tasks, load: &HashSet<usize>) -> Option<Update> {
+        let mut update = Update {
+            workers: None,
+            recurring: None,
+            tasks: vec![],
+        };
+
+        let workers = tasks.get_workers();
+        if self.workers != Some(workers) {
+            self.workers = Some(workers);
+            update.workers = Some(workers);
+        }
+
+        let recurring = tasks.get_recurring();
+        let recurring = recurring.lock().unwrap();
+        let version = tasks.recurring_version();
+        if self.recurring != Some(version) {
+            update.recurring = Some(recurring.clone());
+            self.recurring = Some(version);
+        }
+        drop(recurring);
+
+        // a task that moves between the two while they are looked at may
+        // be seen in both, or in neither and then sent with the next update
+        for (finished, list) in [
+            (false, tasks.get_currently_running()),
+            (true, tasks.get_finished()),
+        ] {
+            for (id, task) in list.lock().unwrap().iter() {
+                let sent = self.tasks.entry(*id).or_default();
+                let load = load.contains(id);
+                if let Some(task_update) = sent.update(*id, task, finished, load) {
+                    update.tasks.push(task_update);
+                }
+            }
+        }
+
+        let changed =
+            update.workers.is_some() || update.recurring.is_some() || !update.tasks.is_empty();
+        changed.then_some(update)
+    }
+}
+
+impl SentTask {
+    fn update(&mut self, id: usize, task: &Task, finished: bool, load: bool) -> Option<TaskUpdate> {
+        if self.finished {
+            if !load || self.output_sent {
+                return None;
+            }
+            self.output_sent = true;
+            return Some(TaskUpdate {
+                id,
+                state: None,
+                attempts: vec![],
+                attempt_outputs: task
+                    .attempts
+                    .iter()
+                    .map(|attempt| attempt.output.update(None))
+                    .collect(),
+                output: task.output.lock().unwrap().update(None),
+                usage: None,
+            });
+        }
+        // nothing was sent of a task yet as long as its state was not
+        let lazy = finished && self.version.is_none() && !load;
+        self.finished = finished;
+        self.output_sent = !lazy;
+
+        // a task that just finished changed at least in that
+        let state = (finished || self.version != Some(task.version)).then(|| {
+            self.version = Some(task.version);
+            TaskState::new(task, finished)
+        });
+
+        let attempts: Vec<AttemptState> = task.attempts[self.attempts..]
+            .iter()
+            .map(|attempt| AttemptState {
+                start_time: attempt.start_time,
+                finish_time: attempt.finish_time,
+                status: attempt.status.clone(),
+                exit_status: attempt.exit_status.map(|status| status.into_raw()),
+                output: if lazy {
+                    None
+                } else {
+                    attempt.output.update(None)
+                },
+            })
+            .collect();
+        if !attempts.is_empty() {
+            // the output is that of a new attempt now
+            self.attempts = task.attempts.len();
+            self.output = None;
+        }
+
+        let output_update = if lazy {
+            None
+        } else {
+            let output = task.output.lock().unwrap();
+            let output_update = output.update(self.output);
+            self.output = Some(output.position());
+            output_update
+        };
+
+        let usage = (task.usage != self.usage).then(|| {
+            self.usage = task.usage.clone();
+            task.usage.clone()
+        });
+
+        if finished {
+            // nothing is compared with any more
+            self.usage = VecDeque::new();
+        }
+
+        if state.is_none() && attempts.is_empty() && output_update.is_none() && usage.is_none() {
+            return None;
+        }
+        Some(TaskUpdate {
+            id,
+            state,
+            attempts,
+            attempt_outputs: vec![],
+            output: output_update,
+            usage,
+        })
+    }
+}
+
+/// Listens on the Unix socket at `path`, taking over from a daemon that
+/// left its socket behind. Fails if another daemon is still listening on
+/// it. Only the user the daemon runs as may connect, as whoever does can
+/// run commands as that user.
+pub fn bind_socket(path: &Path) -> io::Result<UnixListener> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("a daemon is listening on {} already", path.display()),
+        ));
+    }
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Serves every client connecting to `listener` until one of them asks for
+/// a shutdown, or until the daemon gets SIGTERM, SIGHUP or SIGINT, either
+/// of which stops every task. Returns false if some of them could not be
+/// stopped in time.
+pub fn serve(listener: UnixListener, tasks: Tasks) -> io::Result<bool> {
+    let (stop, stopped) = channel();
+    {
+        let tasks = tasks.clone();
+        let stop = stop.clone();
+        on_termination(move || {
+            let _ = stop.send(tasks.shutdown(SHUTDOWN_TIMEOUT));
+        })?;
+    }
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // the socket file being closed to others is not enough where it
+            // can be replaced by someone else
+            if check_peer(&stream).is_err() {
+                continue;
+            }
+            let tasks = tasks.clone();
+            let stop = stop.clone();
+            thread::spawn(move || serve_client(stream, tasks, stop));
+        }
+    });
+    Ok(stopped.recv().unwrap_or(false))
+}
+
+/// Calls `stop` on another thread once the daemon gets SIGTERM, SIGHUP or
+/// SIGINT, rather than letting them kill it while tasks are running.
+fn on_termination(stop: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for both ends of the pipe
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the read end was just opened, and is owned by nothing else
+    let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+    for signal in [libc::SIGTERM, libc::SIGHUP, libc::SIGINT] {
+        // SAFETY: the handler does nothing but write, which is
+        // async-signal-safe
+        if unsafe { libc::signal(signal, on_signal as *const () as libc::sighandler_t) }
+            == libc::SIG_ERR
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    thread::spawn(move || {
+        if pipe.read(&mut [0]).is_ok() {
+            stop();
+        }
+    });
+    Ok(())
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    // SAFETY: writing a single byte from a valid buffer
+    unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+}
+
+/// Sends a client updates until it disconnects, and handles its requests
+/// meanwhile.
+fn serve_client(stream: UnixStream, tasks: Tasks, stop: Sender<bool>) {
+    let Ok(writer) = stream.try_clone() else {
+        return;
+    };
+    let writer = Arc::new(Mutex::new(writer));
+    // tasks whose output was asked for, sent along with the next update
+    let load = Arc::new(Mutex::new(HashSet::new()));
+
+    let updates = {
+        let tasks = tasks.clone();
+        let writer = writer.clone();
+        let load = load.clone();
+        thread::spawn(move || {
+            let mut sent = Sent::default();
+            loop {
+                let load = mem::take(&mut *load.lock().unwrap());
+                if let Some(update) = sent.update(&tasks, &load) {
+                    if send(&writer, &Message::Update(update)).is_err() {
+                        return;
+                    }
+                }
+                sleep(UPDATE_INTERVAL);
+            }
+        })
+    };
+
+    for line in BufReader::new(&stream).lines() {
+        let Ok(request) = line.and_then(|line| Ok(serde_json::from_str(&line)?)) else {
+            break;
+        };
+        let shutdown = matches!(request, Request::Shutdown);
+        let ok = match request {
+            Request::Submit { mut spec, env } => {
+                spec.inherited_env = env;
+                let result = tasks.submit(*spec);
+                if send(&writer, &Message::Submitted { result }).is_err() {
+                    break;
+                }
+                continue;
+            }
+            Request::Cancel { id } => tasks.cancel(id),
+            Request::TogglePause { id } => tasks.toggle_pause(id),
+            Request::SetPriority { id, priority } => tasks.set_priority(id, priority),
+            Request::SendInput { id, input } => tasks.send_input(id, input),
+            Request::LoadOutput { id } => {
+                load.lock().unwrap().insert(id);
+                tasks.get_finished().lock().unwrap().contains_key(&id)
+            }
+            Request::RemoveRecurring { id } => tasks.remove_recurring(id),
+            Request::SetWorkers { limit } => {
+                tasks.set_workers(limit);
+                true
+            }
+            Request::SetGroupLimit { name, limit } => {
+                tasks.set_group_limit(&name, limit);
+                true
+            }
+            Request::Shutdown => tasks.shutdown(SHUTDOWN_TIMEOUT),
+        };
+        let sent = send(&writer, &Message::Done { ok });
+        // the client that asked is told first, the daemon exits right after
+        if shutdown {
+            let _ = stop.send(ok);
+        }
+        if sent.is_err() {
+            break;
+        }
+    }
+    // ends the updates as well
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = updates.join();
+}
+
+/// Fails unless the process on the other end of `stream` runs as the same
+/// user as this one.
+pub(crate) fn check_peer(stream: &UnixStream) -> io::Result<()> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: credentials is valid for writes of len bytes
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: getuid cannot fail
+    if credentials.uid != unsafe { libc::getuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the other end of the socket runs as another user",
+        ));
+    }
+    Ok(())
+}
+
+/// Sends bytes as base64 rather than as an array of numbers, which takes
+/// several times the space in JSON.
+pub(crate) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(D::Error::custom)
+    }
+}
+
+/// Writes a message as a single line, so that those of different threads
+/// do not mix.
+fn send(writer: &Mutex<UnixStream>, message: &Message) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.lock().unwrap().write_all(&line)
+}
@@ -0,0 +1,51 @@
+use crate::CommandStatus;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Which outcomes of a prerequisite let a dependent task run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DependencyKind {
+    OnSuccess,
+    OnFailure,
+    /// Either outcome, as long as the prerequisite actually ran to the end.
+    OnCompletion,
+}
+
+/// An edge from a task to a task that has to finish before it can start.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dependency {
+    pub id: usize,
+    pub kind: DependencyKind,
+}
+
+impl Dependency {
+    /// Whether a prerequisite that finished with `status` lets the dependent
+    /// task run. Cancelled and skipped prerequisites never do.
+    pub fn met_by(&self, status: &CommandStatus) -> bool {
+        let succeeded = matches!(status, CommandStatus::Succeeded);
+        let failed = matches!(
+            status,
+            CommandStatus::Exited(_)
+                | CommandStatus::Signalled { .. }
+                | CommandStatus::TimedOut(_)
+                | CommandStatus::LimitExceeded(_)
+                | CommandStatus::Failed(_)
+        );
+        match self.kind {
+            DependencyKind::OnSuccess => succeeded,
+            DependencyKind::OnFailure => failed,
+            DependencyKind::OnCompletion => succeeded || failed,
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            DependencyKind::OnSuccess => "success",
+            DependencyKind::OnFailure => "failure",
+            DependencyKind::OnCompletion => "completion",
+        };
+        write!(f, "#{} ({kind})", self.id)
+    }
+}
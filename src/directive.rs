@@ -0,0 +1,581 @@
+use crate::parse::next_word;
+use crate::{
+    is_variable_name, parse_duration, parse_size, split_command, Backoff, CapPolicy, Client, Cron,
+    Dependency, DependencyKind, ExecMode, Overlap, ParseError, Recurrence, Resource, StreamCap,
+    TaskSpec,
+};
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeZone};
+use std::{env, path::PathBuf, time::Duration};
+
+/// An option typed in front of a command, e.g. `:shell bash` in
+/// `:shell bash make 2>&1 | tee build.log`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    Shell(String),
+    Direct,
+    /// `None` turns a default timeout off again.
+    Timeout(Option<Duration>),
+    /// Total number of attempts for a task that does not succeed.
+    Retry(u32),
+    Backoff(Backoff),
+    /// Exit codes to retry on, empty to retry on any failure.
+    RetryOn(Vec<i32>),
+    /// Tasks that have to finish in a certain way before this one runs.
+    After(DependencyKind, Vec<usize>),
+    Priority(i32),
+    /// Number of tasks that may run at once. Applies to the worker pool
+    /// rather than to tasks.
+    Workers(usize),
+    /// Directory to run in, made absolute when the directive is parsed.
+    Cd(PathBuf),
+    Env(String, String),
+    Unset(String),
+    /// `None` stops using a default env file.
+    EnvFile(Option<PathBuf>),
+    /// Whether to run under a pseudo-terminal.
+    Pty(bool),
+    /// Limits to set, `None` lifting a default limit again.
+    Limit(Vec<(Resource, Option<u64>)>),
+    /// `None` goes back to ctm's own nice value.
+    Nice(Option<i32>),
+    /// When to queue the task, from either `:at` or `:in`.
+    StartAt(DateTime<Local>),
+    /// Makes the task recur, from either `:every` or `:cron`.
+    Recur(Recurrence),
+    Overlap(Overlap),
+    /// Concurrency group to join, and the number of its tasks that may run
+    /// at once if given. `None` leaves a default group again.
+    Group(Option<(String, Option<usize>)>),
+    /// `None` lifts a default cap again.
+    StreamCap(Option<StreamCap>),
+}
+
+impl Directive {
+    /// Number of arguments the directive takes, which are separated by
+    /// whitespace and quoted like the words of a command.
+    fn arity(name: &str) -> Option<usize> {
+        match name {
+            "shell" => Some(1),
+            "direct" | "pty" | "nopty" => Some(0),
+            "timeout" | "retry" | "backoff" | "retry-on" => Some(1),
+            "after" | "after-fail" | "after-done" => Some(1),
+            "prio" | "priority" => Some(1),
+            "workers" => Some(1),
+            "cd" | "env" | "unset" | "envfile" => Some(1),
+            "limit" | "nice" => Some(1),
+            "at" | "in" => Some(1),
+            "every" | "overlap" => Some(1),
+            "group" | "output" => Some(1),
+            // the five fields of a cron expression
+            "cron" => Some(5),
+            _ => None,
+        }
+    }
+
+    /// Whether the directive only makes sense for a single task, and so
+    /// cannot be made a default.
+    fn is_per_task(&self) -> bool {
+        matches!(
+            self,
+            Directive::After(..) | Directive::StartAt(_) | Directive::Recur(_)
+        )
+    }
+
+    /// Whether the directive changes global settings, and so cannot be
+    /// given for a single task.
+    fn is_global(&self) -> bool {
+        matches!(self, Directive::Workers(_))
+    }
+
+    fn parse(name: &str, args: &[&str]) -> Result<Directive, ParseError> {
+        match name {
+            "shell" => Ok(Directive::Shell(args[0].to_string())),
+            "direct" => Ok(Directive::Direct),
+            "pty" => Ok(Directive::Pty(true)),
+            "nopty" => Ok(Directive::Pty(false)),
+            "timeout" => match args[0] {
+                "off" | "none" => Ok(Directive::Timeout(None)),
+                arg => parse_duration(arg)
+                    .filter(|timeout| !timeout.is_zero())
+                    .map(|timeout| Directive::Timeout(Some(timeout)))
+                    .ok_or_else(|| invalid(name, arg)),
+            },
+            "retry" => args[0]
+                .parse()
+                .ok()
+                .filter(|attempts| *attempts > 0)
+                .map(Directive::Retry)
+                .ok_or_else(|| invalid(name, args[0])),
+            "backoff" => {
+                let (kind, delay) = args[0].split_once(':').unwrap_or(("fixed", args[0]));
+                let delay = parse_duration(delay).ok_or_else(|| invalid(name, args[0]))?;
+                match kind {
+                    "fixed" => Ok(Directive::Backoff(Backoff::Fixed(delay))),
+                    "exp" => Ok(Directive::Backoff(Backoff::Exponential(delay))),
+                    _ => Err(invalid(name, args[0])),
+                }
+            }
+            "retry-on" => match args[0] {
+                "any" => Ok(Directive::RetryOn(vec![])),
+                arg => arg
+                    .split(',')
+                    .map(|code| code.parse())
+                    .collect::<Result<_, _>>()
+                    .map(Directive::RetryOn)
+                    .map_err(|_| invalid(name, arg)),
+            },
+            "after" | "after-fail" | "after-done" => {
+                let kind = match name {
+                    "after" => DependencyKind::OnSuccess,
+                    "after-fail" => DependencyKind::OnFailure,
+                    _ => DependencyKind::OnCompletion,
+                };
+                args[0]
+                    .split(',')
+                    .map(|id| id.trim_start_matches('#').parse())
+                    .collect::<Result<_, _>>()
+                    .map(|ids| Directive::After(kind, ids))
+                    .map_err(|_| invalid(name, args[0]))
+            }
+            "prio" | "priority" => args[0]
+                .parse()
+                .map(Directive::Priority)
+                .map_err(|_| invalid(name, args[0])),
+            "workers" => args[0]
+                .parse()
+                .ok()
+                .filter(|workers| *workers > 0)
+                .map(Directive::Workers)
+                .ok_or_else(|| invalid(name, args[0])),
+            "cd" => Ok(Directive::Cd(parse_path(args[0]))),
+            "env" => match args[0].split_once('=') {
+                Some((var, value)) if is_variable_name(var) => {
+                    Ok(Directive::Env(var.to_string(), value.to_string()))
+                }
+                _ => Err(invalid(name, args[0])),
+            },
+            "unset" if is_variable_name(args[0]) => Ok(Directive::Unset(args[0].to_string())),
+            "unset" => Err(invalid(name, args[0])),
+            "envfile" => match args[0] {
+                "off" | "none" => Ok(Directive::EnvFile(None)),
+                arg => Ok(Directive::EnvFile(Some(parse_path(arg)))),
+            },
+            "limit" if matches!(args[0], "off" | "none") => Ok(Directive::Limit(
+                Resource::ALL.map(|resource| (resource, None)).to_vec(),
+            )),
+            "limit" => args[0]
+                .split(',')
+                .map(|limit| parse_limit(limit).ok_or_else(|| invalid(name, args[0])))
+                .collect::<Result<_, _>>()
+                .map(Directive::Limit),
+            "nice" => match args[0] {
+                "off" | "none" => Ok(Directive::Nice(None)),
+                arg => arg
+                    .parse()
+                    .ok()
+                    .filter(|nice| (-20..=19).contains(nice))
+                    .map(|nice| Directive::Nice(Some(nice)))
+                    .ok_or_else(|| invalid(name, arg)),
+            },
+            "at" => parse_time(args[0])
+                .map(Directive::StartAt)
+                .ok_or_else(|| invalid(name, args[0])),
+            "in" => parse_duration(args[0])
+                .and_then(|delay| chrono::Duration::from_std(delay).ok())
+                .map(|delay| Directive::StartAt(Local::now() + delay))
+                .ok_or_else(|| invalid(name, args[0])),
+            "every" => parse_duration(args[0])
+                .filter(|interval| *interval >= Duration::from_secs(1))
+                .map(|interval| Directive::Recur(Recurrence::Every(interval)))
+                .ok_or_else(|| invalid(name, args[0])),
+            "cron" => Cron::parse(args)
+                .map(|cron| Directive::Recur(Recurrence::Cron(cron)))
+                .ok_or_else(|| invalid(name, &args.join(" "))),
+            "overlap" => match args[0] {
+                "skip" => Ok(Directive::Overlap(Overlap::Skip)),
+                "queue" => Ok(Directive::Overlap(Overlap::Queue)),
+                "allow" => Ok(Directive::Overlap(Overlap::Allow)),
+                arg => Err(invalid(name, arg)),
+            },
+            "group" => match args[0].split_once('=') {
+                _ if matches!(args[0], "off" | "none") => Ok(Directive::Group(None)),
+                Some((group, limit)) if !group.is_empty() => limit
+                    .parse()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .map(|limit| Directive::Group(Some((group.to_string(), Some(limit)))))
+                    .ok_or_else(|| invalid(name, args[0])),
+                Some(_) => Err(invalid(name, args[0])),
+                None => Ok(Directive::Group(Some((args[0].to_string(), None)))),
+            },
+            "output" if matches!(args[0], "off" | "none") => Ok(Directive::StreamCap(None)),
+            "output" => {
+                let (policy, limit) = args[0].split_once(':').unwrap_or(("tail", args[0]));
+                let policy = match policy {
+                    "head" => CapPolicy::Head,
+                    "tail" => CapPolicy::Tail,
+                    "kill" => CapPolicy::Kill,
+                    _ => return Err(invalid(name, args[0])),
+                };
+                parse_size(limit)
+                    .filter(|limit| *limit > 0)
+                    .map(|limit| Directive::StreamCap(Some(StreamCap { limit, policy })))
+                    .ok_or_else(|| invalid(name, args[0]))
+            }
+            _ => Err(ParseError::UnknownDirective(name.to_string())),
+        }
+    }
+}
+
+fn invalid(name: &str, arg: &str) -> ParseError {
+    ParseError::InvalidArgument(name.to_string(), arg.to_string())
+}
+
+/// Parses a single `resource=value` of `:limit`. CPU time may be given as
+/// a duration, and is rounded up to whole seconds.
+fn parse_limit(limit: &str) -> Option<(Resource, Option<u64>)> {
+    let (resource, value) = limit.split_once('=')?;
+    let resource = Resource::from_name(resource)?;
+    let value = match (resource, value) {
+        (_, "off" | "none") => None,
+        (Resource::Memory | Resource::Core, size) => Some(parse_size(size)?),
+        (Resource::Cpu, duration) => {
+            let duration = parse_duration(duration).filter(|duration| !duration.is_zero())?;
+            Some(duration.as_secs() + u64::from(duration.subsec_nanos() > 0))
+        }
+        (Resource::Files, files) => Some(files.parse().ok()?),
+    };
+    Some((resource, value))
+}
+
+/// Parses the argument of `:at`, either a time of day such as `02:00` or
+/// `14:30:15`, which is the next time the clock shows it, or a date and
+/// time such as `2024-05-01T02:00`, which must not have passed yet.
+fn parse_time(time: &str) -> Option<DateTime<Local>> {
+    let now = Local::now();
+    let date_time = ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(time, format).ok());
+    if let Some(date_time) = date_time {
+        let date_time = Local.from_local_datetime(&date_time).earliest()?;
+        return (date_time > now).then_some(date_time);
+    }
+
+    let time = ["%H:%M", "%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveTime::parse_from_str(time, format).ok())?;
+    let mut date = now.date_naive();
+    if time <= now.time() {
+        date = date.succ_opt()?;
+    }
+    Local.from_local_datetime(&date.and_time(time)).earliest()
+}
+
+/// Makes a path argument absolute, so that it still points to the same
+/// place when shown or used later on.
+fn parse_path(arg: &str) -> PathBuf {
+    let path = PathBuf::from(arg);
+    match env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path,
+    }
+}
+
+impl TaskSpec {
+    /// Applies a directive to this spec, either for a single task or to
+    /// the defaults used for every task submitted afterwards.
+    pub fn apply(&mut self, directive: &Directive) {
+        match directive {
+            Directive::Shell(shell) => self.exec_mode = ExecMode::Shell(shell.clone()),
+            Directive::Direct => self.exec_mode = ExecMode::Direct,
+            Directive::Timeout(timeout) => self.timeout = *timeout,
+            Directive::Retry(attempts) => self.retry.max_attempts = *attempts,
+            Directive::Backoff(backoff) => self.retry.backoff = *backoff,
+            Directive::RetryOn(codes) => self.retry.retry_on = codes.clone(),
+            Directive::After(kind, ids) => {
+                self.dependencies.extend(ids.iter().map(|id| Dependency {
+                    id: *id,
+                    kind: *kind,
+                }))
+            }
+            Directive::Priority(priority) => self.priority = *priority,
+            Directive::Workers(_) => {}
+            Directive::Cd(cwd) => self.cwd = Some(cwd.clone()),
+            Directive::Env(var, value) => self.env.push((var.clone(), Some(value.clone()))),
+            Directive::Unset(var) => self.env.push((var.clone(), None)),
+            Directive::EnvFile(env_file) => self.env_file = env_file.clone(),
+            Directive::Pty(pty) => self.pty = *pty,
+            Directive::Limit(limits) => {
+                for (resource, limit) in limits {
+                    self.limits.set(*resource, *limit);
+                }
+            }
+            Directive::Nice(nice) => self.nice = *nice,
+            Directive::StartAt(start_at) => self.start_at = Some(*start_at),
+            Directive::Recur(recurrence) => self.recurrence = Some(recurrence.clone()),
+            Directive::Overlap(overlap) => self.overlap = *overlap,
+            Directive::Group(group) => self.group = group.as_ref().map(|(name, _)| name.clone()),
+            Directive::StreamCap(cap) => self.stream_cap = *cap,
+        }
+    }
+}
+
+/// Splits a line typed into the command line into the leading directives
+/// and the command that follows them. The command is returned untouched so
+/// that it can still be handed to a shell verbatim; it is `None` when the
+/// line only contains directives. A `:` not followed by a name, as in
+/// `: true`, is part of the command.
+///
+/// Arguments of directives are words like those of a command, quoted and
+/// expanded the same way.
+pub fn parse_input(line: &str) -> Result<(Vec<Directive>, Option<String>), ParseError> {
+    let mut directives = vec![];
+    let mut rest = line.trim_start();
+
+    while let Some(stripped) = rest
+        .strip_prefix(':')
+        .filter(|stripped| stripped.starts_with(|c: char| !c.is_whitespace()))
+    {
+        let mut words = stripped.splitn(2, char::is_whitespace);
+        let name = words.next().unwrap_or_default();
+        rest = words.next().unwrap_or_default().trim_start();
+
+        let arity =
+            Directive::arity(name).ok_or_else(|| ParseError::UnknownDirective(name.to_string()))?;
+        let mut args = vec![];
+        for _ in 0..arity {
+            let (arg, after) = next_word(rest);
+            if arg.is_empty() {
+                return Err(ParseError::MissingArgument(name.to_string()));
+            }
+            // a single word as typed makes a single word once expanded
+            args.extend(split_command(arg)?);
+            rest = after;
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        directives.push(Directive::parse(name, &args)?);
+    }
+
+    if rest.trim().is_empty() {
+        if directives.is_empty() {
+            return Err(ParseError::Empty);
+        }
+        Ok((directives, None))
+    } else {
+        Ok((directives, Some(rest.trim_end().to_string())))
+    }
+}
+
+/// Turns a line typed into the command line into the task to queue.
+/// A line holding nothing but directives changes `defaults` and the
+/// worker pool of the daemon behind `client` instead, in which case `None`
+/// is returned. Group limits given either way apply to every task of the group.
+pub fn submit_input(
+    line: &str,
+    defaults: &mut TaskSpec,
+    client: &Client,
+) -> Result<Option<TaskSpec>, ParseError> {
+    let (directives, command) = parse_input(line)?;
+    let Some(command) = command else {
+        if directives.iter().any(Directive::is_per_task) {
+            return Err(ParseError::MissingCommand);
+        }
+        for directive in &directives {
+            match directive {
+                Directive::Workers(workers) => client.set_workers(*workers),
+                directive => defaults.apply(directive),
+            }
+        }
+        set_group_limits(&directives, client);
+        return Ok(None);
+    };
+    if directives.iter().any(Directive::is_global) {
+        return Err(ParseError::UnexpectedCommand);
+    }
+
+    let mut spec = defaults.clone();
+    for directive in &directives {
+        spec.apply(directive);
+    }
+    // shells report their own syntax errors, but direct commands have to be
+    // split by us, so reject them early if that is going to fail
+    if spec.exec_mode == ExecMode::Direct {
+        split_command(&command)?;
+    }
+    set_group_limits(&directives, client);
+    spec.command = command;
+    Ok(Some(spec))
+}
+
+fn set_group_limits(directives: &[Directive], client: &Client) {
+    for directive in directives {
+        if let Directive::Group(Some((name, Some(limit)))) = directive {
+            client.set_group_limit(name, *limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> (Vec<Directive>, Option<String>) {
+        parse_input(line).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_command_as_typed() {
+        assert_eq!(
+            parse(":prio 5   echo  \"a  b\" | wc "),
+            (
+                vec![Directive::Priority(5)],
+                Some("echo  \"a  b\" | wc".to_string())
+            )
+        );
+        assert_eq!(parse("  ls -l"), (vec![], Some("ls -l".to_string())));
+    }
+
+    #[test]
+    fn quoted_arguments_may_hold_whitespace() {
+        assert_eq!(
+            parse(":env CFLAGS=\"-O2 -g\" make"),
+            (
+                vec![Directive::Env("CFLAGS".to_string(), "-O2 -g".to_string())],
+                Some("make".to_string())
+            )
+        );
+        assert_eq!(
+            parse(":cd \"/tmp/my dir\" :shell 'sh' ls"),
+            (
+                vec![
+                    Directive::Cd(PathBuf::from("/tmp/my dir")),
+                    Directive::Shell("sh".to_string())
+                ],
+                Some("ls".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn a_colon_without_a_name_is_a_command() {
+        assert_eq!(parse(": true"), (vec![], Some(": true".to_string())));
+        assert_eq!(parse(":"), (vec![], Some(":".to_string())));
+        assert_eq!(
+            parse(":direct : true"),
+            (vec![Directive::Direct], Some(": true".to_string()))
+        );
+    }
+
+    #[test]
+    fn directives_alone_change_the_defaults() {
+        assert_eq!(
+            parse(":workers 4 :timeout off"),
+            (vec![Directive::Workers(4), Directive::Timeout(None)], None)
+        );
+    }
+
+    #[test]
+    fn reports_what_is_wrong() {
+        assert_eq!(parse_input("  "), Err(ParseError::Empty));
+        assert_eq!(
+            parse_input(":nope ls"),
+            Err(ParseError::UnknownDirective("nope".to_string()))
+        );
+        assert_eq!(
+            parse_input(":timeout "),
+            Err(ParseError::MissingArgument("timeout".to_string()))
+        );
+        assert_eq!(parse_input(":retry 0 ls"), Err(invalid("retry", "0")));
+        assert_eq!(
+            parse_input(":cd \"/tmp ls"),
+            Err(ParseError::UnterminatedDoubleQuote(1))
+        );
+        assert_eq!(
+            parse_input(":env \"A B\"=1 ls"),
+            Err(invalid("env", "A B=1"))
+        );
+    }
+
+    fn parse_one(name: &str, arg: &str) -> Result<Directive, ParseError> {
+        Directive::parse(name, &[arg])
+    }
+
+    #[test]
+    fn parses_retries_and_dependencies() {
+        assert_eq!(
+            parse_one("backoff", "exp:2s"),
+            Ok(Directive::Backoff(Backoff::Exponential(
+                Duration::from_secs(2)
+            )))
+        );
+        assert_eq!(
+            parse_one("retry-on", "1,75"),
+            Ok(Directive::RetryOn(vec![1, 75]))
+        );
+        assert_eq!(
+            parse_one("after-fail", "#3,4"),
+            Ok(Directive::After(DependencyKind::OnFailure, vec![3, 4]))
+        );
+        assert_eq!(parse_one("after", "3,x"), Err(invalid("after", "3,x")));
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(
+            parse_one("limit", "mem=1G,cpu=1500ms,files=off"),
+            Ok(Directive::Limit(vec![
+                (Resource::Memory, Some(1 << 30)),
+                (Resource::Cpu, Some(2)),
+                (Resource::Files, None),
+            ]))
+        );
+        assert_eq!(parse_one("limit", "mem"), Err(invalid("limit", "mem")));
+        assert_eq!(parse_one("nice", "20"), Err(invalid("nice", "20")));
+    }
+
+    #[test]
+    fn parses_recurrences() {
+        assert_eq!(
+            parse(":cron 0 2 * * 1-5 :overlap queue"),
+            (
+                vec![
+                    Directive::Recur(Recurrence::Cron(
+                        Cron::parse(&["0", "2", "*", "*", "1-5"]).unwrap()
+                    )),
+                    Directive::Overlap(Overlap::Queue)
+                ],
+                None
+            )
+        );
+        assert_eq!(parse_one("every", "500ms"), Err(invalid("every", "500ms")));
+    }
+
+    #[test]
+    fn parses_groups() {
+        assert_eq!(
+            parse_one("group", "build=2"),
+            Ok(Directive::Group(Some(("build".to_string(), Some(2)))))
+        );
+        assert_eq!(parse_one("group", "=2"), Err(invalid("group", "=2")));
+        assert_eq!(parse_one("group", "off"), Ok(Directive::Group(None)));
+    }
+
+    #[test]
+    fn parses_output_caps() {
+        assert_eq!(
+            parse_one("output", "head:1K"),
+            Ok(Directive::StreamCap(Some(StreamCap {
+                limit: 1024,
+                policy: CapPolicy::Head,
+            })))
+        );
+        assert_eq!(
+            parse_one("output", "1M"),
+            Ok(Directive::StreamCap(Some(StreamCap {
+                limit: 1 << 20,
+                policy: CapPolicy::Tail,
+            })))
+        );
+        assert_eq!(parse_one("output", "0"), Err(invalid("output", "0")));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::TaskSpec;
+use std::{fs, path::Path, process::Command};
+
+/// Whether `name` can be used as the name of an environment variable.
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Reads the `KEY=value` lines of a `.env` file. Blank lines and lines
+/// starting with `#` are ignored, as is a leading `export`. Values may be
+/// quoted; inside double quotes `\n`, `\"` and `\\` are unescaped.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+
+    let mut vars = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let invalid = || format!("{}:{}: expected KEY=value", path.display(), number + 1);
+
+        let (name, value) = line.split_once('=').ok_or_else(invalid)?;
+        let name = name.trim();
+        if !is_variable_name(name) {
+            return Err(invalid());
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = unquote(value, '\'') {
+            quoted.to_string()
+        } else if let Some(quoted) = unquote(value, '"') {
+            unescape(quoted)
+        } else {
+            // unquoted values end at a comment
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+        vars.push((name.to_string(), value));
+    }
+    Ok(vars)
+}
+
+fn unquote(value: &str, quote: char) -> Option<&str> {
+    value
+        .strip_prefix(quote)
+        .and_then(|value| value.strip_suffix(quote))
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some(c @ ('"' | '\\')) => unescaped.push(c),
+                Some(c) => {
+                    unescaped.push('\\');
+                    unescaped.push(c);
+                }
+                None => unescaped.push('\\'),
+            },
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+impl TaskSpec {
+    /// Sets the working directory and environment of the process to spawn
+    /// for this task. The env file is read now rather than on submission,
+    /// so a task can use one written by a task it depends on.
+    pub fn apply_environment(&self, command: &mut Command) -> Result<(), String> {
+        if let Some(vars) = &self.inherited_env {
+            command
+                .env_clear()
+                .envs(vars.iter().map(|(name, value)| (name, value)));
+        }
+        if let Some(env_file) = &self.env_file {
+            command.envs(read_env_file(env_file)?);
+        }
+        for (name, value) in &self.env {
+            match value {
+                Some(value) => command.env(name, value),
+                None => command.env_remove(name),
+            };
+        }
+        if let Some(cwd) = &self.cwd {
+            // spawning fails with the same error for a missing directory as
+            // for a missing program, so tell them apart here
+            if !cwd.is_dir() {
+                return Err(format!("{}: no such directory", cwd.display()));
+            }
+            command.current_dir(cwd);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, path::PathBuf};
+
+    /// Writes `contents` to an env file of its own for the test `name`.
+    fn env_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ctm-test-{}-{name}.env", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn read(name: &str, contents: &str) -> Result<Vec<(String, String)>, String> {
+        let path = env_file(name, contents);
+        let vars = read_env_file(&path);
+        fs::remove_file(path).unwrap();
+        vars
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        assert_eq!(
+            read("comments", "# settings\n\n  A=1\nexport B = 2 \n"),
+            Ok(vars(&[("A", "1"), ("B", "2")]))
+        );
+    }
+
+    #[test]
+    fn unquotes_values() {
+        assert_eq!(
+            read(
+                "quotes",
+                "A='a $b \\n'\nB=\"a\\n\\\"b\\\" \\\\ \\t\"\nC=a b # note\nD=a#b\nE=\n"
+            ),
+            Ok(vars(&[
+                ("A", "a $b \\n"),
+                ("B", "a\n\"b\" \\ \\t"),
+                ("C", "a b"),
+                ("D", "a#b"),
+                ("E", ""),
+            ]))
+        );
+    }
+
+    #[test]
+    fn reports_the_line_that_is_wrong() {
+        for (name, contents, line) in [("name", "A=1\n1A=2\n", 2), ("no-value", "A\n", 1)] {
+            let path = env_file(name, contents);
+            let err = read_env_file(&path).unwrap_err();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(
+                err,
+                format!("{}:{line}: expected KEY=value", path.display())
+            );
+        }
+        assert!(read_env_file(Path::new("/nonexistent/.env")).is_err());
+    }
+}
@@ -0,0 +1,515 @@
+use crate::output::create_private;
+use crate::{CommandStatus, OutputStream, Recurring, Task, TaskOutput, TaskSpec, Tasks};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{OpenOptionsExt, PermissionsExt},
+        process::ExitStatusExt,
+    },
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+
+/// A change to the tasks, as recorded in the journal.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Submitted {
+        id: usize,
+        spec: TaskSpec,
+        /// Recurring task this task is a run of.
+        parent: Option<usize>,
+    },
+    /// The first attempt of a task started.
+    Started {
+        id: usize,
+        time: DateTime<Local>,
+    },
+    Priority {
+        id: usize,
+        priority: i32,
+    },
+    Finished {
+        id: usize,
+        status: CommandStatus,
+        /// Raw wait status of the process, if there was one.
+        exit_status: Option<i32>,
+        start_time: DateTime<Local>,
+        finish_time: Option<DateTime<Local>>,
+    },
+    Recurring {
+        id: usize,
+        spec: TaskSpec,
+        /// Runs skipped so far, only set in a compacted journal.
+        #[serde(default)]
+        skipped: u32,
+    },
+    /// A run of a recurring task was skipped, as the previous one was
+    /// still going.
+    RecurringSkipped {
+        id: usize,
+    },
+    RecurringRemoved {
+        id: usize,
+    },
+}
+
+/// An append-only file of JSON lines, one `Event` per line, from which the
+/// tasks are restored when ctm starts again.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if there is none yet, and
+    /// reads the events recorded in it so far. A line that cannot be read
+    /// back, such as one cut short by a crash, is skipped.
+    fn open(path: &Path) -> io::Result<(Journal, Vec<Event>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        // one created by an earlier version may still be readable by others
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        let mut events = vec![];
+        // lines are split as bytes, one cut short in the middle of a
+        // character must not keep the others from being read
+        for line in BufReader::new(&file).split(b'\n') {
+            if let Ok(event) = serde_json::from_slice(&line?) {
+                events.push(event);
+            }
+        }
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file,
+        };
+        Ok((journal, events))
+    }
+
+    fn record(&mut self, event: &Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        // a single write per event, so that a crash leaves at most the last
+        // line incomplete
+        self.file.write_all(&line)
+    }
+
+    /// Replaces everything recorded so far with `events`. The new journal
+    /// is written next to the old one and then moved over it, so that one
+    /// of the two is always complete.
+    fn rewrite(&mut self, events: &[Event]) -> io::Result<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut temp = create_private(Path::new(&temp_path))?;
+        for event in events {
+            let mut line = serde_json::to_vec(event)?;
+            line.push(b'\n');
+            temp.write_all(&line)?;
+        }
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// What the journal says about a single task.
+struct Restored {
+    spec: TaskSpec,
+    parent: Option<usize>,
+    started: Option<DateTime<Local>>,
+    finished: Option<Finished>,
+}
+
+/// How a restored task ended.
+struct Finished {
+    status: CommandStatus,
+    exit_status: Option<i32>,
+    start_time: DateTime<Local>,
+    finish_time: Option<DateTime<Local>>,
+}
+
+impl Tasks {
+    /// Restores the tasks recorded in the journal at `path` and records
+    /// every change from now on in it. Finished tasks come back as they
+    /// ended, tasks that never started are queued again, and tasks that
+    /// were running when ctm exited are marked `Interrupted`. Recurring
+    /// tasks keep recurring. Of a task that was retried, only the output
+    /// of the last attempt comes back, as the earlier attempts are not
+    /// recorded.
+    ///
+    /// Has to be called before any task is submitted, as ids carry on
+    /// from where the journal left off, and after `set_log_dir` for the
+    /// output of restored tasks to be found.
+    pub fn open_journal(&self, path: &Path) -> io::Result<()> {
+        let (mut journal, events) = Journal::open(path)?;
+
+        let mut tasks = BTreeMap::new();
+        let mut recurring = BTreeMap::new();
+        for event in events {
+            match event {
+                Event::Submitted { id, spec, parent } => {
+                    let task = Restored {
+                        spec,
+                        parent,
+                        started: None,
+                        finished: None,
+                    };
+                    tasks.insert(id, task);
+                }
+                Event::Started { id, time } => {
+                    if let Some(task) = tasks.get_mut(&id) {
+                        task.started = Some(time);
+                    }
+                }
+                Event::Priority { id, priority } => {
+                    if let Some(task) = tasks.get_mut(&id) {
+                        task.spec.priority = priority;
+                    }
+                }
+                Event::Finished {
+                    id,
+                    status,
+                    exit_status,
+                    start_time,
+                    finish_time,
+                } => {
+                    if let Some(task) = tasks.get_mut(&id) {
+                        // only a compacted journal lacks the start event
+                        task.started.get_or_insert(start_time);
+                        task.finished = Some(Finished {
+                            status,
+                            exit_status,
+                            start_time,
+                            finish_time,
+                        });
+                    }
+                }
+                Event::Recurring { id, spec, skipped } => {
+                    recurring.insert(id, (spec, skipped));
+                }
+                Event::RecurringSkipped { id } => {
+                    if let Some((_, skipped)) = recurring.get_mut(&id) {
+                        *skipped += 1;
+                    }
+                }
+                Event::RecurringRemoved { id } => {
+                    recurring.remove(&id);
+                }
+            }
+        }
+
+        let next_id = tasks
+            .keys()
+            .chain(recurring.keys())
+            .max()
+            .map_or(0, |id| id + 1);
+        self.next_id.fetch_max(next_id, Ordering::Relaxed);
+
+        // the restored state is all the new journal needs to hold
+        let mut compacted = vec![];
+        let now = Local::now();
+        for (id, (spec, skipped)) in recurring {
+            let runs: Vec<usize> = tasks
+                .iter()
+                .filter(|(_, task)| task.parent == Some(id))
+                .map(|(run, _)| *run)
+                .collect();
+            let Some(recurrence) = spec.recurrence.clone() else {
+                continue;
+            };
+            // carry on from the last run rather than starting over
+            let last_run = runs.iter().filter_map(|run| tasks[run].started).max();
+            let next_run = match last_run {
+                Some(last_run) => recurrence.next(last_run, now),
+                None => recurrence.first(spec.start_at.map_or(now, |start_at| start_at.max(now))),
+            };
+            compacted.push(Event::Recurring {
+                id,
+                spec: spec.clone(),
+                skipped,
+            });
+            let restored = Recurring {
+                spec,
+                next_run,
+                runs,
+                skipped,
+                pending: false,
+            };
+            self.recurring.lock().unwrap().insert(id, restored);
+        }
+
+        for (id, task) in tasks {
+            compacted.push(Event::Submitted {
+                id,
+                spec: task.spec.clone(),
+                parent: task.parent,
+            });
+            let finished = match (task.finished, task.started) {
+                (Some(finished), _) => finished,
+                (None, Some(start_time)) => Finished {
+                    status: CommandStatus::Interrupted,
+                    exit_status: None,
+                    start_time,
+                    finish_time: None,
+                },
+                (None, None) => {
+                    self.enqueue(id, task.spec, task.parent);
+                    continue;
+                }
+            };
+            compacted.push(Event::Finished {
+                id,
+                status: finished.status.clone(),
+                exit_status: finished.exit_status,
+                start_time: finished.start_time,
+                finish_time: finished.finish_time,
+            });
+            let mut restored = Task::new(task.spec);
+            restored.parent = task.parent;
+            restored.status = finished.status;
+            restored.exit_status = finished.exit_status.map(ExitStatus::from_raw);
+            restored.start_time = finished.start_time;
+            restored.finish_time = finished.finish_time;
+            if let Some(output) = self.restore_output(id) {
+                restored.output = Arc::new(Mutex::new(output));
+            }
+            self.finished.lock().unwrap().insert(id, restored);
+        }
+        {
+            // the scheduler cannot miss the wakeup while its lock is held
+            let _scheduled = self.scheduled.lock().unwrap();
+            self.schedule_changed.notify_one();
+        }
+
+        journal.rewrite(&compacted)?;
+        *self.journal.lock().unwrap() = Some(journal);
+        Ok(())
+    }
+
+    /// Output of the last attempt of a task, as found in the log files.
+    fn restore_output(&self, id: usize) -> Option<TaskOutput> {
+        let mut output = None;
+        for attempt in 1.. {
+            let Some((stdout, stderr)) = self.log_paths(id, attempt) else {
+                break;
+            };
+            let Some(stdout) = OutputStream::from_log(stdout) else {
+                break;
+            };
+            let stderr = OutputStream::from_log(stderr).unwrap_or_default();
+            output = Some(TaskOutput { stdout, stderr });
+        }
+        output
+    }
+
+    /// Records a change in the journal, if there is one. A journal that
+    /// cannot be written to any more is given up on rather than keeping
+    /// tasks from running.
+    pub(crate) fn record(&self, event: Event) {
+        let mut journal = self.journal.lock().unwrap();
+        if let Some(writer) = journal.as_mut() {
+            if writer.record(&event).is_err() {
+                *journal = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Recurrence;
+    use std::{cmp::Reverse, env, process, time::Duration};
+
+    fn spec(command: &str) -> TaskSpec {
+        TaskSpec {
+            command: command.to_string(),
+            ..TaskSpec::default()
+        }
+    }
+
+    fn finished(id: usize, status: CommandStatus, exit_status: Option<i32>) -> Event {
+        Event::Finished {
+            id,
+            status,
+            exit_status,
+            start_time: Local::now(),
+            finish_time: Some(Local::now()),
+        }
+    }
+
+    /// A journal of its own for the test `name`, holding `lines`.
+    fn journal(name: &str, lines: &[Vec<u8>]) -> PathBuf {
+        let path = env::temp_dir().join(format!("ctm-test-{}-{name}.journal", process::id()));
+        let mut contents = vec![];
+        for line in lines {
+            contents.extend(line);
+            contents.push(b'\n');
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn restore(path: &Path) -> Tasks {
+        let tasks = Tasks::default();
+        tasks.open_journal(path).unwrap();
+        tasks
+    }
+
+    /// Checks that `tasks` hold what was recorded in `history`, be it
+    /// restored from the journal as written or as compacted.
+    fn assert_restored(tasks: &Tasks) {
+        let finished = tasks.finished.lock().unwrap();
+        assert_eq!(finished.keys().copied().collect::<Vec<_>>(), [0, 1, 4]);
+        assert!(matches!(finished[&0].status, CommandStatus::Succeeded));
+        assert_eq!(
+            finished[&0].exit_status.map(|status| status.into_raw()),
+            Some(0)
+        );
+        assert!(matches!(finished[&1].status, CommandStatus::Interrupted));
+        assert_eq!(finished[&4].parent, Some(3));
+
+        let running = tasks.currently_running.lock().unwrap();
+        assert_eq!(running.keys().copied().collect::<Vec<_>>(), [2]);
+        assert!(matches!(running[&2].status, CommandStatus::InQueue));
+        let queue = tasks.queue.lock().unwrap();
+        assert_eq!(queue.keys().copied().collect::<Vec<_>>(), [(Reverse(5), 2)]);
+
+        let recurring = tasks.recurring.lock().unwrap();
+        assert_eq!(recurring.keys().copied().collect::<Vec<_>>(), [3]);
+        assert_eq!(recurring[&3].runs, [4]);
+        assert_eq!(recurring[&3].skipped, 2);
+        assert!(recurring[&3].next_run.is_some());
+
+        // nothing refers to the recurring task that was removed any more,
+        // so its id may be given out again
+        assert_eq!(tasks.next_id.load(Ordering::Relaxed), 5);
+    }
+
+    fn history() -> Vec<Vec<u8>> {
+        let every_minute = TaskSpec {
+            recurrence: Some(Recurrence::Every(Duration::from_secs(60))),
+            ..spec("date")
+        };
+        let events = [
+            Event::Submitted {
+                id: 0,
+                spec: spec("true"),
+                parent: None,
+            },
+            Event::Started {
+                id: 0,
+                time: Local::now(),
+            },
+            finished(0, CommandStatus::Succeeded, Some(0)),
+            Event::Submitted {
+                id: 1,
+                spec: spec("sleep 60"),
+                parent: None,
+            },
+            Event::Started {
+                id: 1,
+                time: Local::now(),
+            },
+            Event::Submitted {
+                id: 2,
+                spec: spec("echo queued"),
+                parent: None,
+            },
+            Event::Priority { id: 2, priority: 5 },
+            Event::Recurring {
+                id: 3,
+                spec: every_minute.clone(),
+                skipped: 0,
+            },
+            Event::Submitted {
+                id: 4,
+                spec: spec("date"),
+                parent: Some(3),
+            },
+            Event::Started {
+                id: 4,
+                time: Local::now(),
+            },
+            finished(4, CommandStatus::Succeeded, Some(0)),
+            Event::RecurringSkipped { id: 3 },
+            Event::RecurringSkipped { id: 3 },
+            Event::Recurring {
+                id: 5,
+                spec: every_minute,
+                skipped: 0,
+            },
+            Event::RecurringRemoved { id: 5 },
+        ];
+        events
+            .iter()
+            .map(|event| serde_json::to_vec(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replays_the_history_of_the_tasks() {
+        let path = journal("replay", &history());
+        let tasks = restore(&path);
+        fs::remove_file(&path).unwrap();
+        assert_restored(&tasks);
+    }
+
+    #[test]
+    fn skips_lines_that_cannot_be_read() {
+        let mut lines = history();
+        // cut short by a crash, in the middle of a character
+        let last = lines.pop().unwrap();
+        lines.push(b"{\"event\":\"started\",\"id\":\xe2\x82".to_vec());
+        lines.insert(3, b"not json".to_vec());
+        lines.push(last);
+        let path = journal("invalid", &lines);
+        let tasks = restore(&path);
+        fs::remove_file(&path).unwrap();
+        assert_restored(&tasks);
+    }
+
+    #[test]
+    fn compacts_to_what_was_restored() {
+        let path = journal("compact", &history());
+        drop(restore(&path));
+
+        let (_, events) = Journal::open(&path).unwrap();
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|event| match event {
+                Event::Submitted { .. } => "submitted",
+                Event::Finished { .. } => "finished",
+                Event::Recurring { .. } => "recurring",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "recurring",
+                "submitted",
+                "finished",
+                "submitted",
+                "finished",
+                "submitted",
+                "submitted",
+                "finished"
+            ]
+        );
+
+        // the interrupted task stays interrupted, and nothing else changes
+        let tasks = restore(&path);
+        fs::remove_file(&path).unwrap();
+        assert_restored(&tasks);
+    }
+}
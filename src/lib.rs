@@ -1,64 +1,970 @@
+mod client;
+mod daemon;
+mod dependency;
+mod directive;
+mod environment;
+mod journal;
+mod limits;
+mod output;
+mod parse;
+mod pty;
+mod recurrence;
+mod retry;
+mod usage;
+
+pub use client::Client;
+pub use daemon::{bind_socket, serve};
+pub use dependency::{Dependency, DependencyKind};
+pub use directive::{parse_input, submit_input, Directive};
+pub use environment::{is_variable_name, read_env_file};
+pub use limits::{format_size, parse_size, Limit, Resource, ResourceLimits};
+pub use output::{CapPolicy, OutputStream, StreamCap, TaskOutput, OUTPUT_TAIL};
+pub use parse::{format_duration, parse_duration, split_command, split_command_with, ParseError};
+pub use recurrence::{Cron, Overlap, Recurrence, Recurring};
+pub use retry::{Backoff, RetryPolicy};
+pub use usage::Usage;
+
 use chrono::{DateTime, Local};
+use journal::{Event, Journal};
+use output::capture;
+use serde::{Deserialize, Serialize};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{
-    collections::{HashMap, VecDeque},
-    fmt,
-    process::{Command, Output},
-    sync::{Arc, Mutex},
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+    env, fmt, fs, io,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+        Arc, Condvar, Mutex, MutexGuard,
+    },
     thread,
 };
 
+/// How often the resource usage of running tasks is sampled.
+const USAGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of usage samples kept per task.
+pub const USAGE_HISTORY: usize = 120;
+
+/// How long a task that is being stopped gets to exit after SIGTERM
+/// before it is killed.
+pub const STOP_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum CommandStatus {
+    /// Waiting for its start time before it is queued.
+    Scheduled(DateTime<Local>),
     InQueue,
     Running,
-    Finished,
+    /// Stopped with SIGSTOP until it is resumed.
+    Paused,
+    /// Waiting for the backoff to pass before the next attempt.
+    Retrying {
+        next_attempt: DateTime<Local>,
+    },
+    /// Exited with status 0.
+    Succeeded,
+    /// Exited with a non-zero status.
+    Exited(i32),
+    /// Killed by a signal.
+    Signalled {
+        signal: i32,
+        core_dumped: bool,
+    },
+    Cancelled,
+    /// Never run because the given prerequisite did not finish as required.
+    Skipped(usize),
+    /// Stopped after running for longer than its timeout.
+    TimedOut(Duration),
+    /// Ended because it ran into one of its resource limits.
+    LimitExceeded(Limit),
+    /// The process could not be started, holds the reason why.
+    Failed(String),
+    /// Was still running when ctm exited or crashed.
+    Interrupted,
+}
+
+impl CommandStatus {
+    pub fn from_exit_status(status: ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (Some(0), _) => CommandStatus::Succeeded,
+            (Some(code), _) => CommandStatus::Exited(code),
+            (None, Some(signal)) => CommandStatus::Signalled {
+                signal,
+                core_dumped: status.core_dumped(),
+            },
+            // a stopped or continued status, which `wait` never returns
+            (None, None) => CommandStatus::Exited(-1),
+        }
+    }
+}
+
+/// Name of a signal as used by `kill -l`, e.g. "SIGKILL".
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGIO => "SIGIO",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("signal {signal}"),
+    };
+    name.to_string()
 }
 
 impl fmt::Display for CommandStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let res = match *self {
+        let res = match self {
+            CommandStatus::Scheduled(start_at) => {
+                let format = if start_at.date_naive() == Local::now().date_naive() {
+                    "%H:%M:%S"
+                } else {
+                    "%Y-%m-%d %H:%M:%S"
+                };
+                return write!(f, "Scheduled for {}", start_at.format(format));
+            }
             CommandStatus::InQueue => "In Queue",
             CommandStatus::Running => "Running",
-            CommandStatus::Finished => "Finished",
+            CommandStatus::Paused => "Paused",
+            CommandStatus::Retrying { next_attempt } => {
+                return write!(f, "Retrying at {}", next_attempt.format("%H:%M:%S"))
+            }
+            CommandStatus::Succeeded => "Succeeded",
+            CommandStatus::Exited(code) => return write!(f, "Failed (exit code {code})"),
+            CommandStatus::Signalled {
+                signal,
+                core_dumped,
+            } => {
+                let core = if *core_dumped { ", core dumped" } else { "" };
+                return write!(f, "Signalled ({}{core})", signal_name(*signal));
+            }
+            CommandStatus::Cancelled => "Cancelled",
+            CommandStatus::Skipped(id) => return write!(f, "Skipped (#{id})"),
+            CommandStatus::TimedOut(elapsed) => {
+                return write!(f, "Timed out after {}", format_duration(*elapsed))
+            }
+            CommandStatus::LimitExceeded(limit) => return write!(f, "Exceeded {limit}"),
+            CommandStatus::Failed(err) => return write!(f, "Failed: {err}"),
+            CommandStatus::Interrupted => "Interrupted",
         };
         write!(f, "{res}")
     }
 }
 
-pub struct Task {
+/// How a task's command line is turned into a process.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ExecMode {
+    /// Split into words by `split_command` and executed directly.
+    #[default]
+    Direct,
+    /// Handed as a whole to `<shell> -c`, so pipes and redirections work.
+    Shell(String),
+}
+
+impl fmt::Display for ExecMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecMode::Direct => write!(f, "none"),
+            ExecMode::Shell(shell) => write!(f, "{shell}"),
+        }
+    }
+}
+
+/// Everything needed to run a task, as submitted from the command line.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+// specs recorded before a field was added still read back
+#[serde(default)]
+pub struct TaskSpec {
     pub command: String,
+    pub exec_mode: ExecMode,
+    pub timeout: Option<Duration>,
+    pub retry: RetryPolicy,
+    /// Tasks that have to finish before this one is started.
+    pub dependencies: Vec<Dependency>,
+    /// Tasks with a higher priority are started first.
+    pub priority: i32,
+    /// Directory to run the task in, ctm's own when `None`.
+    pub cwd: Option<PathBuf>,
+    /// File of `KEY=value` lines added to the environment before `env`.
+    pub env_file: Option<PathBuf>,
+    /// Changes to the inherited environment in the order they were given,
+    /// `None` removing the variable.
+    pub env: Vec<(String, Option<String>)>,
+    /// Environment the task inherits, that of the client it was submitted
+    /// from, in which variables in the command are expanded as well. ctm's
+    /// own when `None`. It may hold secrets, so it is kept in memory only:
+    /// never journaled nor sent back to clients.
+    #[serde(skip)]
+    pub inherited_env: Option<Vec<(String, String)>>,
+    /// Runs the task under a pseudo-terminal instead of with pipes, for
+    /// commands that need a terminal or input typed by the user.
+    pub pty: bool,
+    pub limits: ResourceLimits,
+    /// Nice value to run the task with, ctm's own when `None`.
+    pub nice: Option<i32>,
+    /// When to queue the task, right away when `None`. For recurring
+    /// tasks, when to start recurring.
+    pub start_at: Option<DateTime<Local>>,
+    /// Makes this the definition of a recurring task, which creates a new
+    /// task from this spec every time it is due.
+    pub recurrence: Option<Recurrence>,
+    /// What a recurring task does when it is due while its previous run
+    /// is still going.
+    pub overlap: Overlap,
+    /// Concurrency group the task belongs to, see `Group`.
+    pub group: Option<String>,
+    /// How much of each of the task's output streams is kept, stdout and
+    /// stderr each up to the limit.
+    #[serde(alias = "output_cap")]
+    pub stream_cap: Option<StreamCap>,
+}
+
+impl TaskSpec {
+    /// Builds the process to spawn for this task.
+    pub fn build_command(&self) -> Result<Command, ParseError> {
+        match &self.exec_mode {
+            ExecMode::Direct => parse_command(&self.command, &|name| self.inherited_var(name)),
+            ExecMode::Shell(shell) => {
+                let mut command = Command::new(shell);
+                command.arg("-c").arg(&self.command);
+                Ok(command)
+            }
+        }
+    }
+
+    /// A variable of the environment the task inherits.
+    fn inherited_var(&self, name: &str) -> Option<String> {
+        match &self.inherited_env {
+            Some(vars) => vars
+                .iter()
+                .find(|(var, _)| var == name)
+                .map(|(_, value)| value.clone()),
+            None => env::var(name).ok(),
+        }
+    }
+}
+
+/// A finished attempt of a task that was retried afterwards.
+pub struct Attempt {
+    pub start_time: DateTime<Local>,
+    pub finish_time: DateTime<Local>,
+    pub status: CommandStatus,
+    pub exit_status: Option<ExitStatus>,
+    pub output: TaskOutput,
+}
+
+pub struct Task {
+    pub spec: TaskSpec,
     pub start_time: DateTime<Local>,
     pub finish_time: Option<DateTime<Local>>,
     pub status: CommandStatus,
-    pub output: Option<Output>,
+    pub exit_status: Option<ExitStatus>,
+    pub output: Arc<Mutex<TaskOutput>>,
+    pub pid: Option<u32>,
+    /// Set when the user asked to cancel the task while it was running.
+    pub cancel_requested: bool,
+    /// Set while the user wants the running task paused.
+    pub pause_requested: bool,
+    /// Time the current attempt spent paused, which does not count towards
+    /// its execution time or timeout.
+    pub paused_time: Duration,
+    /// Earlier attempts, the current one is described by the fields above.
+    pub attempts: Vec<Attempt>,
+    /// Recurring task this task is a run of.
+    pub parent: Option<usize>,
+    /// Process group the task's processes run in, which is also their
+    /// session. Everything in it is stopped along with the task.
+    pub pgid: Option<u32>,
+    /// Sends input to the task while it runs under a pseudo-terminal.
+    pub input: Option<Sender<Vec<u8>>>,
+    /// Whether the task takes input, also known to clients, which only
+    /// have a copy of the task and no `input`.
+    pub accepts_input: bool,
+    /// Resource usage of the task's processes, sampled every
+    /// `USAGE_INTERVAL` while they run. Holds the last `USAGE_HISTORY`
+    /// samples, oldest first.
+    pub usage: VecDeque<Usage>,
+    /// Counts the changes to the fields above other than `output`,
+    /// `attempts` and `usage`, so that clients are only sent the state of
+    /// a task when it changed.
+    pub(crate) version: usize,
+}
+
+impl Task {
+    fn new(spec: TaskSpec) -> Self {
+        Task {
+            spec,
+            start_time: Local::now(),
+            finish_time: None,
+            status: CommandStatus::InQueue,
+            exit_status: None,
+            output: Arc::new(Mutex::new(TaskOutput::default())),
+            pid: None,
+            cancel_requested: false,
+            pause_requested: false,
+            paused_time: Duration::ZERO,
+            attempts: vec![],
+            parent: None,
+            pgid: None,
+            input: None,
+            accepts_input: false,
+            usage: VecDeque::new(),
+            version: 0,
+        }
+    }
+
+    /// Number of the current attempt, starting at 1.
+    pub fn attempt(&self) -> u32 {
+        self.attempts.len() as u32 + 1
+    }
+}
+
+/// Queued tasks ordered by priority, highest first. Task ids grow with
+/// every submission, so within a priority level tasks stay in the order
+/// they were submitted in.
+type Queue = BTreeMap<(Reverse<i32>, usize), TaskSpec>;
+
+/// Tasks waiting for their start time, soonest first.
+type Schedule = BTreeMap<(DateTime<Local>, usize), TaskSpec>;
+
+/// A named limit on how many tasks tagged with it may run at once, no
+/// matter how many workers are idle. Tasks over the limit stay queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Group {
+    pub limit: usize,
+    pub running: usize,
+}
+
+impl Default for Group {
+    /// Groups nobody gave a limit let a single task run at a time.
+    fn default() -> Self {
+        Group {
+            limit: 1,
+            running: 0,
+        }
+    }
+}
+
+/// Size of the worker pool. `alive` only exceeds `limit` for a while after
+/// the pool was shrunk, until the surplus workers are done with their
+/// current task.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Workers {
+    pub limit: usize,
+    pub alive: usize,
+    pub busy: usize,
 }
 
 #[derive(Default)]
 pub struct Tasks {
-    queue: Arc<Mutex<VecDeque<(usize, String)>>>,
-    currently_running: Arc<Mutex<HashMap<usize, Task>>>,
-    finished: Arc<Mutex<HashMap<usize, Task>>>,
+    /// Id of the next task or recurring task.
+    next_id: Arc<AtomicUsize>,
+    recurring: Arc<Mutex<BTreeMap<usize, Recurring>>>,
+    /// Counts the changes to `recurring`, see `Task::version`.
+    recurring_version: Arc<AtomicUsize>,
+    scheduled: Arc<Mutex<Schedule>>,
+    /// Signalled whenever a task is scheduled, so the scheduler can look
+    /// at its start time.
+    schedule_changed: Arc<Condvar>,
+    queue: Arc<Mutex<Queue>>,
+    /// Signalled whenever a queued task may have become ready to run,
+    /// idle workers block on it instead of polling the queue.
+    queue_changed: Arc<Condvar>,
+    workers: Arc<Mutex<Workers>>,
+    /// Where changes to the tasks are recorded, if anywhere.
+    journal: Arc<Mutex<Option<Journal>>>,
+    /// Directory the output of tasks is written to, if any.
+    log_dir: Arc<Mutex<Option<PathBuf>>>,
+    groups: Arc<Mutex<BTreeMap<String, Group>>>,
+    currently_running: Arc<Mutex<BTreeMap<usize, Task>>>,
+    finished: Arc<Mutex<BTreeMap<usize, Task>>>,
 }
 
 impl Tasks {
-    pub fn get_currently_running(&self) -> Arc<Mutex<HashMap<usize, Task>>> {
+    pub fn get_currently_running(&self) -> Arc<Mutex<BTreeMap<usize, Task>>> {
         self.currently_running.clone()
     }
 
-    pub fn get_finished(&self) -> Arc<Mutex<HashMap<usize, Task>>> {
+    pub fn get_finished(&self) -> Arc<Mutex<BTreeMap<usize, Task>>> {
         self.finished.clone()
     }
 
-    pub fn push_queue(&self, id: usize, cmd: String) {
-        self.queue.lock().unwrap().push_back((id, cmd));
+    pub fn get_recurring(&self) -> Arc<Mutex<BTreeMap<usize, Recurring>>> {
+        self.recurring.clone()
+    }
+
+    /// Changes to the recurring tasks so far. Read with them locked, as
+    /// the count goes up only once a change was made.
+    pub(crate) fn recurring_version(&self) -> usize {
+        self.recurring_version.load(Ordering::Relaxed)
+    }
+
+    fn recurring_changed(&self) {
+        self.recurring_version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Queues a task, or sets up a recurring one if the spec has a
+    /// recurrence. Returns the id it was given.
+    pub fn submit(&self, spec: TaskSpec) -> Result<usize, ParseError> {
+        if spec.recurrence.is_none() {
+            return self.push_queue(spec);
+        }
+        self.check_dependencies(&spec)?;
+        let recurrence = spec.recurrence.clone().unwrap();
+        let now = Local::now();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.record(Event::Recurring {
+            id,
+            spec: spec.clone(),
+            skipped: 0,
+        });
+        let recurring = Recurring {
+            next_run: recurrence.first(spec.start_at.map_or(now, |start_at| start_at.max(now))),
+            spec,
+            runs: vec![],
+            skipped: 0,
+            pending: false,
+        };
+        // the scheduler looks at recurring tasks with `scheduled` locked,
+        // holding it keeps the wakeup from getting lost
+        let _scheduled = self.scheduled.lock().unwrap();
+        self.recurring.lock().unwrap().insert(id, recurring);
+        self.recurring_changed();
+        self.schedule_changed.notify_one();
+        Ok(id)
+    }
+
+    /// Stops a recurring task from creating new runs. Runs that were
+    /// created already are left alone. Returns false if there is no such
+    /// recurring task.
+    pub fn remove_recurring(&self, id: usize) -> bool {
+        let removed = self.recurring.lock().unwrap().remove(&id).is_some();
+        if removed {
+            self.recurring_changed();
+            self.record(Event::RecurringRemoved { id });
+        }
+        removed
+    }
+
+    fn check_dependencies(&self, spec: &TaskSpec) -> Result<(), ParseError> {
+        for dependency in &spec.dependencies {
+            let known = self
+                .currently_running
+                .lock()
+                .unwrap()
+                .contains_key(&dependency.id)
+                || self.finished.lock().unwrap().contains_key(&dependency.id);
+            if !known {
+                return Err(ParseError::UnknownTask(dependency.id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a task and returns the id it was given. Fails if it depends
+    /// on a task that does not exist.
+    pub fn push_queue(&self, spec: TaskSpec) -> Result<usize, ParseError> {
+        self.push_run(spec, None)
+    }
+
+    /// Adds a task to the queue, as a run of the recurring task `parent` if
+    /// there is one.
+    fn push_run(&self, spec: TaskSpec, parent: Option<usize>) -> Result<usize, ParseError> {
+        self.check_dependencies(&spec)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.record(Event::Submitted {
+            id,
+            spec: spec.clone(),
+            parent,
+        });
+        self.enqueue(id, spec, parent);
+        Ok(id)
+    }
+
+    /// Puts a task that was given its id already into the queue, or among
+    /// the scheduled tasks if its start time has not come yet.
+    fn enqueue(&self, id: usize, spec: TaskSpec, parent: Option<usize>) {
+        let mut task = Task::new(spec.clone());
+        task.parent = parent;
+        if let Some(start_at) = spec.start_at.filter(|start_at| *start_at > Local::now()) {
+            task.status = CommandStatus::Scheduled(start_at);
+            let mut scheduled = self.scheduled.lock().unwrap();
+            self.currently_running.lock().unwrap().insert(id, task);
+            scheduled.insert((start_at, id), spec);
+            self.schedule_changed.notify_one();
+            return;
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        self.currently_running.lock().unwrap().insert(id, task);
+        queue.insert((Reverse(spec.priority), id), spec);
+        self.queue_changed.notify_one();
+    }
+
+    /// Moves scheduled tasks into the queue once their start time has
+    /// come, and creates the runs of recurring tasks, for as long as ctm
+    /// runs.
+    fn run_scheduler(&self) {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        loop {
+            let now = Local::now();
+            while let Some(entry) = scheduled.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                let ((_, id), spec) = entry.remove_entry();
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(task) = self.currently_running.lock().unwrap().get_mut(&id) {
+                    task.status = CommandStatus::InQueue;
+                    task.version += 1;
+                }
+                queue.insert((Reverse(spec.priority), id), spec);
+                self.queue_changed.notify_one();
+            }
+
+            let next_run = self.run_recurring(now);
+
+            // the wall clock may jump, so never sleep for too long at once
+            let next = scheduled.keys().next().map(|(start_at, _)| *start_at);
+            let wait = match next.into_iter().chain(next_run).min() {
+                Some(next) => (next - now)
+                    .to_std()
+                    .unwrap_or_default()
+                    .min(Duration::from_secs(60)),
+                None => Duration::from_secs(60),
+            };
+            scheduled = self
+                .schedule_changed
+                .wait_timeout(scheduled, wait)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Creates a run of every recurring task that is due, and of those
+    /// that held one back until their previous run was done. Returns when
+    /// the next one is due.
+    fn run_recurring(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut recurring = self.recurring.lock().unwrap();
+        for (id, recurring) in recurring.iter_mut() {
+            let active = {
+                let running = self.currently_running.lock().unwrap();
+                recurring.runs.iter().any(|run| running.contains_key(run))
+            };
+            let mut start = recurring.pending && !active;
+
+            if let Some(next_run) = recurring.next_run.filter(|next_run| *next_run <= now) {
+                match (active, recurring.spec.overlap) {
+                    (true, Overlap::Skip) => {
+                        recurring.skipped += 1;
+                        self.record(Event::RecurringSkipped { id: *id });
+                    }
+                    (true, Overlap::Queue) => recurring.pending = true,
+                    _ => start = true,
+                }
+                let recurrence = recurring.spec.recurrence.as_ref().unwrap();
+                recurring.next_run = recurrence.next(next_run, now);
+                self.recurring_changed();
+            }
+
+            if start {
+                recurring.pending = false;
+                let spec = TaskSpec {
+                    recurrence: None,
+                    start_at: None,
+                    ..recurring.spec.clone()
+                };
+                if let Ok(run) = self.push_run(spec, Some(*id)) {
+                    recurring.runs.push(run);
+                }
+                self.recurring_changed();
+            }
+        }
+        recurring
+            .values()
+            .filter_map(|recurring| recurring.next_run)
+            .min()
+    }
+
+    /// Changes the priority of a task that is still queued, returns false
+    /// if it is not queued anymore.
+    pub fn set_priority(&self, id: usize, priority: i32) -> bool {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        if let Some(spec) = scheduled
+            .iter_mut()
+            .find(|((_, scheduled), _)| *scheduled == id)
+        {
+            spec.1.priority = priority;
+            if let Some(task) = self.currently_running.lock().unwrap().get_mut(&id) {
+                task.spec.priority = priority;
+                task.version += 1;
+            }
+            self.record(Event::Priority { id, priority });
+            return true;
+        }
+        drop(scheduled);
+
+        let mut queue = self.queue.lock().unwrap();
+        let Some(key) = queue.keys().find(|(_, queued)| *queued == id).copied() else {
+            return false;
+        };
+        let mut spec = queue.remove(&key).unwrap();
+        spec.priority = priority;
+        queue.insert((Reverse(priority), id), spec);
+        if let Some(task) = self.currently_running.lock().unwrap().get_mut(&id) {
+            task.spec.priority = priority;
+            task.version += 1;
+        }
+        self.record(Event::Priority { id, priority });
+        true
+    }
+
+    /// Writes the output of tasks started from now on to log files in
+    /// `dir`, which is created if needed, keeping only the latest part of
+    /// it in memory. Only the owner may look into it.
+    pub fn set_log_dir(&self, dir: PathBuf) -> io::Result<()> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        *self.log_dir.lock().unwrap() = Some(dir);
+        Ok(())
+    }
+
+    /// Output of an attempt of a task, written to log files if there is a
+    /// log directory.
+    fn new_output(&self, id: usize, attempt: u32, cap: Option<StreamCap>) -> TaskOutput {
+        TaskOutput::new(self.log_paths(id, attempt), cap)
+    }
+
+    /// Log files of the stdout and stderr of an attempt of a task.
+    fn log_paths(&self, id: usize, attempt: u32) -> Option<(PathBuf, PathBuf)> {
+        let log_dir = self.log_dir.lock().unwrap();
+        let log_dir = log_dir.as_ref()?;
+        Some((
+            log_dir.join(format!("{id}-{attempt}.stdout")),
+            log_dir.join(format!("{id}-{attempt}.stderr")),
+        ))
+    }
+
+    pub fn get_workers(&self) -> Workers {
+        *self.workers.lock().unwrap()
+    }
+
+    /// Changes the number of tasks that may run at once. Growing the pool
+    /// starts new workers right away, shrinking it lets surplus workers
+    /// exit once they are done with their current task.
+    pub fn set_workers(&self, limit: usize) {
+        // hold the queue lock so that idle workers cannot miss the wakeup
+        // between checking the pool size and waiting
+        let _queue = self.queue.lock().unwrap();
+        let mut workers = self.workers.lock().unwrap();
+        workers.limit = limit;
+        while workers.alive < limit {
+            workers.alive += 1;
+            let tasks = self.clone();
+            thread::spawn(move || worker_loop(tasks));
+        }
+        self.queue_changed.notify_all();
+    }
+
+    pub fn get_groups(&self) -> BTreeMap<String, Group> {
+        self.groups.lock().unwrap().clone()
+    }
+
+    /// Changes how many tasks of a concurrency group may run at once.
+    /// Tasks that are running already keep running when it is lowered.
+    pub fn set_group_limit(&self, name: &str, limit: usize) {
+        let _queue = self.queue.lock().unwrap();
+        let mut groups = self.groups.lock().unwrap();
+        groups.entry(name.to_string()).or_default().limit = limit;
+        self.queue_changed.notify_all();
+    }
+
+    /// Lets the next task of a concurrency group run once a task of it is
+    /// done.
+    fn leave_group(&self, name: &str) {
+        // hold the queue lock so that idle workers cannot miss the wakeup
+        let _queue = self.queue.lock().unwrap();
+        if let Some(group) = self.groups.lock().unwrap().get_mut(name) {
+            group.running = group.running.saturating_sub(1);
+        }
+        self.queue_changed.notify_all();
+    }
+
+    /// Blocks until a queued task is ready to run and takes it off the
+    /// queue. Checking and taking happen under a single lock, so no two
+    /// workers can ever end up with the same task. Returns `None` when the
+    /// pool has been shrunk and the calling worker should exit instead.
+    fn next_ready(&self) -> Option<(usize, TaskSpec)> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            {
+                let mut workers = self.workers.lock().unwrap();
+                if workers.alive > workers.limit {
+                    workers.alive -= 1;
+                    return None;
+                }
+            }
+            let (task, skipped) = self.pop_ready(&mut queue);
+            if skipped {
+                // the scheduler may be holding back a run of a recurring task
+                // until a skipped one is done, and is woken up under its own
+                // lock, which comes before the queue's
+                drop(queue);
+                let _scheduled = self.scheduled.lock().unwrap();
+                self.schedule_changed.notify_one();
+                queue = self.queue.lock().unwrap();
+            }
+            if let Some(task) = task {
+                self.workers.lock().unwrap().busy += 1;
+                return Some(task);
+            }
+            if !skipped {
+                queue = self.queue_changed.wait(queue).unwrap();
+            }
+        }
+    }
+
+    /// Takes the first queued task whose prerequisites have all finished as
+    /// required, and whose concurrency group is not full, off the queue.
+    /// Tasks with a prerequisite that finished any other way are skipped
+    /// along the way, which in turn gets their own dependents skipped. Also
+    /// returns whether any were.
+    fn pop_ready(&self, queue: &mut MutexGuard<Queue>) -> (Option<(usize, TaskSpec)>, bool) {
+        let mut skipped = false;
+        loop {
+            let mut groups = self.groups.lock().unwrap();
+            let finished = self.finished.lock().unwrap();
+
+            let mut skip = None;
+            let mut ready = None;
+            for (key, spec) in queue.iter() {
+                let id = key.1;
+                let unmet = spec.dependencies.iter().find(|dependency| {
+                    finished
+                        .get(&dependency.id)
+                        .is_some_and(|task| !dependency.met_by(&task.status))
+                });
+                if let Some(dependency) = unmet {
+                    skip = Some((*key, id, dependency.id));
+                    break;
+                }
+                let waiting = spec
+                    .dependencies
+                    .iter()
+                    .any(|dependency| !finished.contains_key(&dependency.id));
+                let group_full = spec.group.as_ref().is_some_and(|name| {
+                    let group = groups.get(name).copied().unwrap_or_default();
+                    group.running >= group.limit
+                });
+                if !waiting && !group_full {
+                    ready = Some(*key);
+                    break;
+                }
+            }
+            drop(finished);
+
+            if let Some(name) = ready.and_then(|key| queue[&key].group.as_ref()) {
+                groups.entry(name.clone()).or_default().running += 1;
+            }
+            drop(groups);
+
+            match (skip, ready) {
+                (Some((key, id, prerequisite)), _) => {
+                    queue.remove(&key);
+                    self.move_to_finished(id, CommandStatus::Skipped(prerequisite), None);
+                    self.queue_changed.notify_all();
+                    skipped = true;
+                }
+                (None, Some(key)) => {
+                    return (queue.remove(&key).map(|spec| (key.1, spec)), skipped)
+                }
+                (None, None) => return (None, skipped),
+            }
+        }
+    }
+
+    /// Cancels a task. Scheduled and queued tasks are taken off right away,
+    /// running ones get SIGTERM and are killed if they are still around
+    /// after `STOP_GRACE_PERIOD`. Returns false if the task is neither
+    /// queued nor running.
+    pub fn cancel(&self, id: usize) -> bool {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        if let Some(key) = scheduled
+            .keys()
+            .find(|(_, scheduled)| *scheduled == id)
+            .copied()
+        {
+            scheduled.remove(&key);
+            drop(scheduled);
+            self.finish(id, CommandStatus::Cancelled, None);
+            return true;
+        }
+        // `scheduled` stays locked, so that the scheduler cannot move the
+        // task into the queue while we look for it there
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(key) = queue.keys().find(|(_, queued)| *queued == id).copied() {
+            queue.remove(&key);
+            drop(queue);
+            drop(scheduled);
+            self.finish(id, CommandStatus::Cancelled, None);
+            return true;
+        }
+        drop(queue);
+
+        // the worker running the task sends the signals, as it is the only
+        // one that knows whether the process has been reaped already
+        match self.currently_running.lock().unwrap().get_mut(&id) {
+            Some(task) => {
+                task.cancel_requested = true;
+                task.version += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends input to a task running under a pseudo-terminal. Returns
+    /// false if the task is not running or does not take input.
+    pub fn send_input(&self, id: usize, input: Vec<u8>) -> bool {
+        match self.currently_running.lock().unwrap().get(&id) {
+            Some(Task {
+                input: Some(sender),
+                ..
+            }) => sender.send(input).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Pauses a running task, or resumes it if it is paused already.
+    /// Returns false if the task is not running.
+    pub fn toggle_pause(&self, id: usize) -> bool {
+        match self.currently_running.lock().unwrap().get_mut(&id) {
+            Some(task) if matches!(task.status, CommandStatus::Running | CommandStatus::Paused) => {
+                // like cancelling, the signals are left to the worker
+                task.pause_requested = !task.pause_requested;
+                task.version += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Cancels every task, queued or running, and waits for at most
+    /// `timeout` for the workers to stop them and exit. Returns false if
+    /// some of them did not manage to in time.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        // what is stopped now is not meant to stay stopped, so it is left
+        // out of the journal: queued tasks are queued again on the next
+        // start, and running ones show up as interrupted
+        self.journal.lock().unwrap().take();
+        self.recurring.lock().unwrap().clear();
+        self.recurring_changed();
+        self.set_workers(0);
+        let ids: Vec<usize> = self
+            .currently_running
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        for id in ids {
+            self.cancel(id);
+        }
+        let started = Instant::now();
+        while self.workers.lock().unwrap().alive > 0 {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        true
+    }
+
+    /// Moves a task from `currently_running` to `finished`. Tasks waiting
+    /// for this one may be ready now, and so may a run of a recurring task
+    /// that was held back until this one is done.
+    fn finish(&self, id: usize, status: CommandStatus, exit_status: Option<ExitStatus>) {
+        // hold the locks the scheduler and idle workers look at the tasks
+        // under, so that neither can miss the wakeups
+        let _scheduled = self.scheduled.lock().unwrap();
+        let _queue = self.queue.lock().unwrap();
+        self.move_to_finished(id, status, exit_status);
+        self.queue_changed.notify_all();
+        self.schedule_changed.notify_one();
+    }
+
+    /// Moves a task from `currently_running` to `finished`, leaving it to
+    /// the caller to wake up whoever may be waiting for that.
+    fn move_to_finished(&self, id: usize, status: CommandStatus, exit_status: Option<ExitStatus>) {
+        let task = self.currently_running.lock().unwrap().remove(&id);
+        if let Some(mut task) = task {
+            task.status = status;
+            task.exit_status = exit_status;
+            task.finish_time = Some(Local::now());
+            task.pid = None;
+            task.pgid = None;
+            task.version += 1;
+            self.record(Event::Finished {
+                id,
+                status: task.status.clone(),
+                exit_status: task.exit_status.map(|status| status.into_raw()),
+                start_time: task.start_time,
+                finish_time: task.finish_time,
+            });
+            self.finished.lock().unwrap().insert(id, task);
+        }
     }
 }
 
 impl Clone for Tasks {
     fn clone(&self) -> Self {
         Tasks {
+            next_id: Arc::clone(&self.next_id),
+            recurring: Arc::clone(&self.recurring),
+            recurring_version: Arc::clone(&self.recurring_version),
+            scheduled: Arc::clone(&self.scheduled),
+            schedule_changed: Arc::clone(&self.schedule_changed),
             queue: Arc::clone(&self.queue),
+            queue_changed: Arc::clone(&self.queue_changed),
+            workers: Arc::clone(&self.workers),
+            journal: Arc::clone(&self.journal),
+            log_dir: Arc::clone(&self.log_dir),
+            groups: Arc::clone(&self.groups),
             currently_running: Arc::clone(&self.currently_running),
             finished: Arc::clone(&self.finished),
         }
@@ -66,69 +972,495 @@ impl Clone for Tasks {
 }
 
 fn worker_loop(tasks: Tasks) {
-    loop {
-        let tasks_clone = tasks.clone();
-        if !tasks.queue.lock().unwrap().is_empty() {
-            let (id, command) = tasks.queue.lock().unwrap().pop_front().unwrap();
-            run_command(id, command.as_str(), tasks_clone);
+    while let Some((id, spec)) = tasks.next_ready() {
+        let group = spec.group.clone();
+        run_command(id, spec, tasks.clone());
+        if let Some(group) = group {
+            tasks.leave_group(&group);
         }
-        sleep(Duration::from_millis(10));
+        tasks.workers.lock().unwrap().busy -= 1;
     }
 }
 
 pub fn spawn_threads(num_threads: usize, tasks: Tasks) {
-    for _ in 0..num_threads {
-        let tasks_clone = tasks.clone();
-        thread::spawn(|| worker_loop(tasks_clone));
+    tasks.set_workers(num_threads);
+    thread::spawn(move || tasks.run_scheduler());
+}
+
+/// Builds a `Command` from a command line, see `split_command` for the
+/// syntax, looking variables up with `var`.
+fn parse_command(cmd: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<Command, ParseError> {
+    let words = split_command_with(cmd, var)?;
+    let mut command = Command::new(&words[0]);
+    command.args(&words[1..]);
+    Ok(command)
+}
+
+/// Updates the status of a running task along with its paused time.
+fn set_status(tasks: &Tasks, id: usize, status: CommandStatus, paused_time: Duration) {
+    if let Some(task) = tasks.currently_running.lock().unwrap().get_mut(&id) {
+        task.status = status;
+        task.paused_time = paused_time;
+        task.version += 1;
+    }
+}
+
+/// Whether a child process has exited, without reaping it.
+fn has_exited(pid: u32) -> io::Result<bool> {
+    // SAFETY: a zeroed siginfo_t is valid, and waitid only writes to it
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let options = libc::WEXITED | libc::WNOWAIT | libc::WNOHANG;
+    if unsafe { libc::waitid(libc::P_PID, pid, &mut info, options) } == -1 {
+        return Err(io::Error::last_os_error());
     }
+    // SAFETY: waitid filled in the pid of a child that exited, or left it 0
+    Ok(unsafe { info.si_pid() } != 0)
 }
 
-pub fn get_output(id: usize, tasks: Tasks) -> Option<Output> {
-    tasks
-        .finished
-        .lock()
-        .unwrap()
-        .get(&id)
-        .unwrap()
-        .output
-        .clone()
+/// Stops whatever is left of a task's process group once its leader exited,
+/// like children it started in the background. They get SIGTERM, and SIGKILL
+/// if they are still around `STOP_GRACE_PERIOD` after the task was first
+/// told to stop. Leftovers would otherwise keep running unseen, and keep
+/// the task's output open.
+fn stop_leftovers(pgid: libc::pid_t, stopping_since: Option<Instant>, kill_sent: bool) {
+    if usage::group_members(pgid as u32).is_empty() {
+        return;
+    }
+    let since = match stopping_since {
+        Some(since) => since,
+        None => {
+            signal_group(pgid, libc::SIGTERM);
+            Instant::now()
+        }
+    };
+    // leftovers of a paused task are stopped as well
+    signal_group(pgid, libc::SIGCONT);
+    let mut kill_sent = kill_sent;
+    while !usage::group_members(pgid as u32).is_empty() {
+        if kill_sent {
+            // processes stuck in the kernel can take a while to die
+            if since.elapsed() >= STOP_GRACE_PERIOD * 2 {
+                break;
+            }
+        } else if since.elapsed() >= STOP_GRACE_PERIOD {
+            signal_group(pgid, libc::SIGKILL);
+            kill_sent = true;
+        }
+        sleep(Duration::from_millis(50));
+    }
 }
 
-fn parse_command(cmd: &str) -> Command {
-    let mut split = cmd.split(' ');
-    let mut command = Command::new(split.next().unwrap());
-    command.args(split);
-    command
+/// Sends a signal to every process in a task's process group.
+fn signal_group(pgid: libc::pid_t, signal: libc::c_int) {
+    // SAFETY: only called while the group leader has not been reaped yet,
+    // so the group id cannot have been reused by another process group
+    unsafe { libc::kill(-pgid, signal) };
 }
 
-// Takes a Command object and execute it to completion
-pub fn run_command(id: usize, cmd: &str, tasks: Tasks) {
-    let mut command = parse_command(cmd);
-    let start_time = Local::now();
-    tasks.currently_running.lock().unwrap().insert(
-        id,
-        Task {
-            command: cmd.to_string(),
-            start_time,
-            finish_time: None,
-            status: CommandStatus::Running,
-            output: None,
-        },
-    );
+// Takes a task and executes an attempt of its command to completion. If
+// its retry policy asks for another attempt, the task is scheduled again
+// for when that is due rather than keeping the worker waiting for it.
+pub fn run_command(id: usize, spec: TaskSpec, tasks: Tasks) {
+    let output = {
+        let mut running = tasks.currently_running.lock().unwrap();
+        let task = running.entry(id).or_insert_with(|| Task::new(spec.clone()));
+        // cancelled while queued or waiting for a retry
+        if task.cancel_requested {
+            drop(running);
+            tasks.finish(id, CommandStatus::Cancelled, None);
+            return;
+        }
+        task.status = CommandStatus::Running;
+        task.start_time = Local::now();
+        *task.output.lock().unwrap() = tasks.new_output(id, task.attempt(), spec.stream_cap);
+        if task.attempts.is_empty() {
+            tasks.record(Event::Started {
+                id,
+                time: task.start_time,
+            });
+        }
+        task.pause_requested = false;
+        task.paused_time = Duration::ZERO;
+        task.version += 1;
+        task.output.clone()
+    };
 
-    let res = command.output();
+    let (status, exit_status) = run_attempt(id, &spec, &tasks, output.clone());
 
-    if let Ok(res) = res {
-        tasks.finished.lock().unwrap().insert(
-            id,
-            Task {
-                command: cmd.to_string(),
-                start_time,
-                finish_time: Some(Local::now()),
-                status: CommandStatus::Finished,
-                output: Some(res),
-            },
+    let mut running = tasks.currently_running.lock().unwrap();
+    let Some(task) = running.get_mut(&id) else {
+        return;
+    };
+    let attempt = task.attempt();
+    if attempt >= spec.retry.max_attempts || !spec.retry.should_retry(&status) {
+        drop(running);
+        tasks.finish(id, status, exit_status);
+        return;
+    }
+
+    let delay = spec.retry.backoff.delay(attempt);
+    let next_attempt =
+        Local::now() + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::zero());
+    task.attempts.push(Attempt {
+        start_time: task.start_time,
+        finish_time: Local::now(),
+        status,
+        exit_status,
+        output: std::mem::take(&mut *output.lock().unwrap()),
+    });
+    task.status = CommandStatus::Retrying { next_attempt };
+    task.version += 1;
+    drop(running);
+    // a task cancelled in between is not found anywhere by `cancel`, and is
+    // finished as cancelled once it is taken off the queue again
+    let mut scheduled = tasks.scheduled.lock().unwrap();
+    scheduled.insert((next_attempt, id), spec);
+    tasks.schedule_changed.notify_one();
+}
+
+/// Runs a single attempt of a task, returning how it ended.
+fn run_attempt(
+    id: usize,
+    spec: &TaskSpec,
+    tasks: &Tasks,
+    output: Arc<Mutex<TaskOutput>>,
+) -> (CommandStatus, Option<ExitStatus>) {
+    let mut command = match spec.build_command() {
+        Ok(command) => command,
+        Err(err) => return (CommandStatus::Failed(err.to_string()), None),
+    };
+    if let Err(err) = spec.apply_environment(&mut command) {
+        return (CommandStatus::Failed(err), None);
+    }
+    spec.apply_limits(&mut command);
+    // the master side of the terminal, and a second handle to it for
+    // writing input, when the task runs under a pseudo-terminal
+    let mut terminal = None;
+    if spec.pty {
+        let master = pty::attach(&mut command).and_then(|master| {
+            let input = master.try_clone()?;
+            Ok((master, input))
+        });
+        match master {
+            Ok(master) => terminal = Some(master),
+            Err(err) => {
+                let err = format!("could not open a terminal: {err}");
+                return (CommandStatus::Failed(err), None);
+            }
+        }
+    } else {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // a session of its own puts the command in a process group of its
+        // own, which lets us stop everything it started and not just the
+        // process we spawned, and keeps it away from ctm's terminal
+        // SAFETY: setsid is async-signal-safe
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    let spawned = command.spawn();
+    // closes our copies of the terminal side of a pseudo-terminal
+    drop(command);
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(err) => return (CommandStatus::Failed(err.to_string()), None),
+    };
+
+    // stdout and stderr are drained on their own threads so neither
+    // pipe can fill up and stall the process while we wait on the other
+    let readers = match terminal {
+        Some((master, input)) => {
+            if let Some(task) = tasks.currently_running.lock().unwrap().get_mut(&id) {
+                task.input = Some(pty::forward_input(input));
+                task.accepts_input = true;
+                task.version += 1;
+            }
+            vec![capture(master, output.clone(), |o| &mut o.stdout)]
+        }
+        None => vec![
+            capture(child.stdout.take().unwrap(), output.clone(), |o| {
+                &mut o.stdout
+            }),
+            capture(child.stderr.take().unwrap(), output.clone(), |o| {
+                &mut o.stderr
+            }),
+        ],
+    };
+    if let Some(task) = tasks.currently_running.lock().unwrap().get_mut(&id) {
+        task.pid = Some(child.id());
+        task.pgid = Some(child.id());
+        task.version += 1;
+    }
+
+    let pgid = child.id() as libc::pid_t;
+    let started = Instant::now();
+    // set once we decided to stop the task: when, and what to report
+    let mut stopping: Option<(Instant, CommandStatus)> = None;
+    let mut kill_sent = false;
+    let mut paused_since: Option<Instant> = None;
+    let mut paused_time = Duration::ZERO;
+    // only needed to tell whether the task ran out of memory
+    let mut peak_memory = 0;
+    let mut sampler = usage::Sampler::new(child.id());
+    let mut sampled: Option<Instant> = None;
+    let exit_status = loop {
+        match has_exited(child.id()) {
+            Ok(true) => {
+                // the group leader stays a zombie until we reap it, which
+                // keeps its group id from being reused while we clean up
+                let stopping_since = stopping.as_ref().map(|(since, _)| *since);
+                stop_leftovers(pgid, stopping_since, kill_sent);
+                break child.wait().ok();
+            }
+            Ok(false) => {}
+            Err(_) => break None,
+        }
+        if sampled.is_none_or(|sampled| sampled.elapsed() >= USAGE_INTERVAL) {
+            sampled = Some(Instant::now());
+            // processes the leader started may be the ones allocating, but
+            // looking at all of them is too costly to do more often
+            if spec.limits.memory.is_some() {
+                peak_memory = peak_memory.max(limits::group_peak_memory(child.id()));
+            }
+            if let Some(usage) = sampler.sample() {
+                if let Some(task) = tasks.currently_running.lock().unwrap().get_mut(&id) {
+                    if task.usage.len() == USAGE_HISTORY {
+                        task.usage.pop_front();
+                    }
+                    task.usage.push_back(usage);
+                }
+            }
+        }
+        if spec.limits.memory.is_some() {
+            peak_memory = peak_memory.max(limits::peak_memory(child.id()).unwrap_or(0));
+        }
+
+        let (cancel_requested, pause_requested) = tasks
+            .currently_running
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map_or((false, false), |task| {
+                (task.cancel_requested, task.pause_requested)
+            });
+
+        if stopping.is_none() {
+            match (pause_requested, paused_since) {
+                (true, None) => {
+                    signal_group(pgid, libc::SIGSTOP);
+                    paused_since = Some(Instant::now());
+                    set_status(tasks, id, CommandStatus::Paused, paused_time);
+                }
+                (false, Some(since)) => {
+                    signal_group(pgid, libc::SIGCONT);
+                    paused_since = None;
+                    paused_time += since.elapsed();
+                    set_status(tasks, id, CommandStatus::Running, paused_time);
+                }
+                _ => {}
+            }
+        }
+        let active_time = started
+            .elapsed()
+            .saturating_sub(paused_time)
+            .saturating_sub(paused_since.map_or(Duration::ZERO, |since| since.elapsed()));
+
+        match &stopping {
+            None => {
+                if cancel_requested {
+                    stopping = Some((Instant::now(), CommandStatus::Cancelled));
+                } else if spec.timeout.is_some_and(|timeout| active_time >= timeout) {
+                    stopping = Some((Instant::now(), CommandStatus::TimedOut(active_time)));
+                } else if let Some(cap) = spec
+                    .stream_cap
+                    .filter(|_| output.lock().unwrap().exceeded())
+                {
+                    let status = CommandStatus::LimitExceeded(Limit::Output(cap.limit));
+                    stopping = Some((Instant::now(), status));
+                }
+                if stopping.is_some() {
+                    signal_group(pgid, libc::SIGTERM);
+                    // stopped processes only act on SIGTERM once continued
+                    if let Some(since) = paused_since.take() {
+                        signal_group(pgid, libc::SIGCONT);
+                        paused_time += since.elapsed();
+                    }
+                }
+            }
+            Some((since, _)) if !kill_sent && since.elapsed() >= STOP_GRACE_PERIOD => {
+                signal_group(pgid, libc::SIGKILL);
+                kill_sent = true;
+            }
+            Some(_) => {}
+        }
+        sleep(Duration::from_millis(50));
+    };
+    for reader in readers {
+        let _ = reader.join();
+    }
+    if let Some(since) = paused_since {
+        paused_time += since.elapsed();
+    }
+    if let Some(task) = tasks.currently_running.lock().unwrap().get_mut(&id) {
+        task.paused_time = paused_time;
+        // lets the thread writing input to the terminal exit
+        task.input = None;
+        task.accepts_input = false;
+        task.version += 1;
+    }
+
+    let status = match (stopping, exit_status) {
+        (Some((_, status)), _) => status,
+        (None, Some(exit_status)) => {
+            let status = CommandStatus::from_exit_status(exit_status);
+            let allocation_failed = spec.limits.memory.is_some() && {
+                let output = output.lock().unwrap();
+                // under a terminal, errors end up on stdout
+                let stream = if spec.pty {
+                    &output.stdout
+                } else {
+                    &output.stderr
+                };
+                limits::reports_allocation_failure(&stream.last(4096))
+            };
+            match spec.exceeded_limit(&status, peak_memory, allocation_failed) {
+                Some(limit) => CommandStatus::LimitExceeded(limit),
+                None => status,
+            }
+        }
+        (None, None) => CommandStatus::Failed("lost track of the process".to_string()),
+    };
+    (status, exit_status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(command: &str) -> TaskSpec {
+        TaskSpec {
+            command: command.to_string(),
+            ..TaskSpec::default()
+        }
+    }
+
+    fn after(kind: DependencyKind, id: usize) -> TaskSpec {
+        TaskSpec {
+            dependencies: vec![Dependency { id, kind }],
+            ..spec("true")
+        }
+    }
+
+    /// Takes the next task that is ready off the queue, as a worker would.
+    fn next(tasks: &Tasks) -> Option<usize> {
+        let mut queue = tasks.queue.lock().unwrap();
+        tasks.pop_ready(&mut queue).0.map(|(id, _)| id)
+    }
+
+    fn skipped_for(tasks: &Tasks, id: usize) -> Option<usize> {
+        match tasks.finished.lock().unwrap().get(&id)?.status {
+            CommandStatus::Skipped(prerequisite) => Some(prerequisite),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn skips_tasks_whose_prerequisites_failed_or_were_cancelled() {
+        let tasks = Tasks::default();
+        let failing = tasks.push_queue(spec("false")).unwrap();
+        let on_success = tasks
+            .push_queue(after(DependencyKind::OnSuccess, failing))
+            .unwrap();
+        let on_failure = tasks
+            .push_queue(after(DependencyKind::OnFailure, failing))
+            .unwrap();
+        let after_skipped = tasks
+            .push_queue(after(DependencyKind::OnCompletion, on_success))
+            .unwrap();
+        let cancelled = tasks.push_queue(spec("sleep 1")).unwrap();
+        let after_cancelled = tasks
+            .push_queue(after(DependencyKind::OnCompletion, cancelled))
+            .unwrap();
+        assert!(tasks.cancel(cancelled));
+
+        assert_eq!(next(&tasks), Some(failing));
+        tasks.finish(failing, CommandStatus::Exited(1), None);
+        assert_eq!(next(&tasks), Some(on_failure));
+        assert_eq!(next(&tasks), None);
+
+        assert_eq!(skipped_for(&tasks, on_success), Some(failing));
+        // skipping a task gets its own dependents skipped in turn
+        assert_eq!(skipped_for(&tasks, after_skipped), Some(on_success));
+        assert_eq!(skipped_for(&tasks, after_cancelled), Some(cancelled));
+        assert!(tasks.queue.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn waits_for_prerequisites_to_finish() {
+        let tasks = Tasks::default();
+        let first = tasks.push_queue(spec("true")).unwrap();
+        let second = tasks
+            .push_queue(after(DependencyKind::OnSuccess, first))
+            .unwrap();
+        assert_eq!(
+            tasks.push_queue(after(DependencyKind::OnSuccess, 7)).err(),
+            Some(ParseError::UnknownTask(7))
         );
+
+        assert_eq!(next(&tasks), Some(first));
+        assert_eq!(next(&tasks), None);
+        tasks.finish(first, CommandStatus::Succeeded, None);
+        assert_eq!(next(&tasks), Some(second));
+    }
+
+    fn queue(tasks: &Tasks, priority: i32, group: Option<&str>) -> usize {
+        let spec = TaskSpec {
+            priority,
+            group: group.map(str::to_string),
+            ..spec("true")
+        };
+        tasks.push_queue(spec).unwrap()
+    }
+
+    #[test]
+    fn takes_higher_priorities_first_and_equal_ones_in_order() {
+        let tasks = Tasks::default();
+        let ids: Vec<usize> = [0, 5, 0, 5, -1]
+            .into_iter()
+            .map(|priority| queue(&tasks, priority, None))
+            .collect();
+        let order: Vec<usize> = std::iter::from_fn(|| next(&tasks)).collect();
+        assert_eq!(order, [ids[1], ids[3], ids[0], ids[2], ids[4]]);
+    }
+
+    #[test]
+    fn a_full_group_lets_the_tasks_behind_it_pass() {
+        let tasks = Tasks::default();
+        let first_build = queue(&tasks, 10, Some("build"));
+        let second_build = queue(&tasks, 10, Some("build"));
+        let ungrouped = queue(&tasks, 0, None);
+        let test = queue(&tasks, -1, Some("test"));
+
+        // groups nobody gave a limit run a single task at a time
+        assert_eq!(next(&tasks), Some(first_build));
+        assert_eq!(next(&tasks), Some(ungrouped));
+        assert_eq!(next(&tasks), Some(test));
+        assert_eq!(next(&tasks), None);
+
+        tasks.leave_group("build");
+        assert_eq!(next(&tasks), Some(second_build));
+        let third_build = queue(&tasks, 0, Some("build"));
+        assert_eq!(next(&tasks), None);
+        tasks.set_group_limit("build", 2);
+        assert_eq!(next(&tasks), Some(third_build));
     }
-    tasks.currently_running.lock().unwrap().remove(&id);
 }
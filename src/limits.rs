@@ -0,0 +1,325 @@
+use crate::{usage::group_members, CommandStatus, TaskSpec};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, os::unix::process::CommandExt, process::Command};
+
+/// A resource whose use by a task can be limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    /// Bytes of address space, per process.
+    Memory,
+    /// Seconds of CPU time, per process.
+    Cpu,
+    /// Number of open file descriptors.
+    Files,
+    /// Bytes of a core dump, 0 to not write any.
+    Core,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 4] = [
+        Resource::Memory,
+        Resource::Cpu,
+        Resource::Files,
+        Resource::Core,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Resource> {
+        match name {
+            "mem" | "memory" => Some(Resource::Memory),
+            "cpu" => Some(Resource::Cpu),
+            "nofile" | "files" => Some(Resource::Files),
+            "core" => Some(Resource::Core),
+            _ => None,
+        }
+    }
+
+    fn rlimit(&self) -> libc::__rlimit_resource_t {
+        match self {
+            Resource::Memory => libc::RLIMIT_AS,
+            Resource::Cpu => libc::RLIMIT_CPU,
+            Resource::Files => libc::RLIMIT_NOFILE,
+            Resource::Core => libc::RLIMIT_CORE,
+        }
+    }
+}
+
+/// Limits set on the processes of a task, `None` leaving ctm's own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    pub memory: Option<u64>,
+    pub cpu: Option<u64>,
+    pub files: Option<u64>,
+    pub core: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn get(&self, resource: Resource) -> Option<u64> {
+        match resource {
+            Resource::Memory => self.memory,
+            Resource::Cpu => self.cpu,
+            Resource::Files => self.files,
+            Resource::Core => self.core,
+        }
+    }
+
+    pub fn set(&mut self, resource: Resource, limit: Option<u64>) {
+        match resource {
+            Resource::Memory => self.memory = limit,
+            Resource::Cpu => self.cpu = limit,
+            Resource::Files => self.files = limit,
+            Resource::Core => self.core = limit,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+}
+
+impl fmt::Display for ResourceLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut limits = vec![];
+        if let Some(memory) = self.memory {
+            limits.push(format!("mem={}", format_size(memory)));
+        }
+        if let Some(cpu) = self.cpu {
+            limits.push(format!("cpu={cpu}s"));
+        }
+        if let Some(files) = self.files {
+            limits.push(format!("nofile={files}"));
+        }
+        if let Some(core) = self.core {
+            limits.push(format!("core={}", format_size(core)));
+        }
+        match limits.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", limits.join(", ")),
+        }
+    }
+}
+
+/// A limit a task was found to have run into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Limit {
+    /// Seconds of CPU time.
+    Cpu(u64),
+    /// Bytes of address space.
+    Memory(u64),
+    /// Bytes of output on either stream.
+    Output(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Cpu(seconds) => write!(f, "CPU time limit of {seconds}s"),
+            Limit::Memory(bytes) => write!(f, "memory limit of {}", format_size(*bytes)),
+            Limit::Output(bytes) => write!(f, "output limit of {} per stream", format_size(*bytes)),
+        }
+    }
+}
+
+/// Parses a size in bytes such as `512`, `64K`, `2G` or `1.5G`, using
+/// binary units. Sizes that do not fit in a `u64` are rejected.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.to_ascii_uppercase();
+    let size = size.strip_suffix('B').unwrap_or(&size);
+    let (number, unit) = match size.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(split) => size.split_at(split),
+        None => (size, ""),
+    };
+    let unit: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    let number: f64 = number
+        .parse()
+        .ok()
+        .filter(|n: &f64| n.is_finite() && *n >= 0.0)?;
+    let bytes = number * unit as f64;
+    // u64::MAX itself is rounded up to 2^64 as a float
+    (bytes < u64::MAX as f64).then_some(bytes as u64)
+}
+
+/// Formats a size in bytes with the largest binary unit it is a whole
+/// multiple of, the way `parse_size` reads it back.
+pub fn format_size(bytes: u64) -> String {
+    for (unit, suffix) in [
+        (1 << 40, "T"),
+        (1 << 30, "G"),
+        (1 << 20, "M"),
+        (1 << 10, "K"),
+    ] {
+        if bytes != 0 && bytes.is_multiple_of(unit) {
+            return format!("{}{suffix}", bytes / unit);
+        }
+    }
+    bytes.to_string()
+}
+
+/// Peak size of the address space of a running process, in bytes.
+pub fn peak_memory(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmPeak:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Largest peak address space of any process in a process group, in
+/// bytes. The memory limit applies to each of them on its own.
+pub fn group_peak_memory(group: u32) -> u64 {
+    group_members(group)
+        .into_iter()
+        .filter_map(peak_memory)
+        .max()
+        .unwrap_or(0)
+}
+
+/// What runtimes and the C library print when an allocation fails.
+const ALLOCATION_FAILURES: [&str; 7] = [
+    "cannot allocate memory",
+    "out of memory",
+    "memory allocation of",
+    "bad_alloc",
+    "memoryerror",
+    "outofmemoryerror",
+    "failed to allocate",
+];
+
+/// Whether some output says that an allocation failed.
+pub fn reports_allocation_failure(output: &[u8]) -> bool {
+    let output = String::from_utf8_lossy(output).to_lowercase();
+    ALLOCATION_FAILURES
+        .iter()
+        .any(|message| output.contains(message))
+}
+
+impl TaskSpec {
+    /// Sets the resource limits and nice value of this task on the process
+    /// to spawn. They are applied in the child right before it executes the
+    /// command, and so are inherited by everything it starts in turn.
+    pub fn apply_limits(&self, command: &mut Command) {
+        if self.limits.is_empty() && self.nice.is_none() {
+            return;
+        }
+        let limits = self.limits;
+        let nice = self.nice;
+        // SAFETY: setrlimit and setpriority are async-signal-safe, and the
+        // closure does not allocate
+        unsafe {
+            command.pre_exec(move || {
+                for resource in Resource::ALL {
+                    let Some(limit) = limits.get(resource) else {
+                        continue;
+                    };
+                    // a hard limit a second above the soft one gets processes
+                    // that ignore SIGXCPU killed shortly after
+                    let hard = match resource {
+                        Resource::Cpu => limit.saturating_add(1),
+                        _ => limit,
+                    };
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit,
+                        rlim_max: hard,
+                    };
+                    if libc::setrlimit(resource.rlimit(), &rlimit) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Tells whether a task that ended with `status` did so because it ran
+    /// into one of its limits. `peak_memory` is the largest address space
+    /// any process of the task was seen using, and `allocation_failed`
+    /// whether the task said that an allocation failed.
+    ///
+    /// Running out of CPU time is signalled by SIGXCPU, which shells report
+    /// as exit code 128 + SIGXCPU. A failed allocation on the other hand is
+    /// only seen by the process itself, so a task that failed is only taken
+    /// to have run out of memory if it came close to the limit or said so.
+    /// Otherwise its exit status is all there is to report.
+    pub fn exceeded_limit(
+        &self,
+        status: &CommandStatus,
+        peak_memory: u64,
+        allocation_failed: bool,
+    ) -> Option<Limit> {
+        if let Some(cpu) = self.limits.cpu {
+            let out_of_time = match status {
+                CommandStatus::Signalled { signal, .. } => *signal == libc::SIGXCPU,
+                CommandStatus::Exited(code) => *code == 128 + libc::SIGXCPU,
+                _ => false,
+            };
+            if out_of_time {
+                return Some(Limit::Cpu(cpu));
+            }
+        }
+        if let Some(memory) = self.limits.memory {
+            let failed = matches!(
+                status,
+                CommandStatus::Exited(_) | CommandStatus::Signalled { .. }
+            );
+            if failed && (peak_memory >= memory / 10 * 9 || allocation_failed) {
+                return Some(Limit::Memory(memory));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_binary_units() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("64K"), Some(64 << 10));
+        assert_eq!(parse_size("64kb"), Some(64 << 10));
+        assert_eq!(parse_size("2M"), Some(2 << 20));
+        assert_eq!(parse_size("1.5G"), Some(3 << 29));
+        assert_eq!(parse_size("1T"), Some(1 << 40));
+        assert_eq!(parse_size("0"), Some(0));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in ["", "K", "-1K", "1P", "1 K", "1e3", "inf", "NaN", "1.2.3"] {
+            assert_eq!(parse_size(size), None, "{size:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_that_overflow() {
+        assert_eq!(parse_size("16777215T"), Some(16777215 << 40));
+        assert_eq!(parse_size("16777216T"), None);
+        assert_eq!(parse_size("18446744073709551616"), None);
+    }
+
+    #[test]
+    fn formatted_sizes_parse_back() {
+        for (bytes, text) in [
+            (0, "0"),
+            (1000, "1000"),
+            (1 << 10, "1K"),
+            (1536, "1536"),
+            (3 << 29, "1536M"),
+            (5 << 40, "5T"),
+        ] {
+            assert_eq!(format_size(bytes), text);
+            assert_eq!(parse_size(text), Some(bytes));
+        }
+    }
+}
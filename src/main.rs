@@ -1,31 +1,41 @@
+mod ansi;
 mod perf;
 
 use async_std::channel::{unbounded, Receiver, Sender};
 use async_std::task;
+use chrono::{DateTime, Local};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use project_root::get_project_root;
 use std::cmp::{max, min, Ordering};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, DirBuilder, File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
+use std::os::unix::{
+    fs::{DirBuilderExt, MetadataExt},
+    process::CommandExt,
+};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::{fmt, io, thread, time::Duration, time::Instant};
+use std::{env, fmt, io, thread, time::Duration, time::Instant};
 use tui::widgets::canvas::{Canvas, Line, Map, MapResolution};
 use tui::{
-    backend::CrosstermBackend,
-    layout::{Alignment, Constraint, Direction, Layout},
+    backend::{Backend, CrosstermBackend},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     symbols,
-    text::{Span, Spans},
+    text::{Span, Spans, Text},
     widgets::*,
-    Terminal,
+    Frame, Terminal,
 };
 extern crate systemstat;
 use systemstat::{saturating_sub_bytes, ByteSize, Platform, System};
@@ -33,10 +43,174 @@ use systemstat::{saturating_sub_bytes, ByteSize, Platform, System};
 use crate::perf::{PerfData, PerfLog};
 use ctm::*;
 
+/// Number of commands that may run at once unless `--workers` says otherwise.
+const DEFAULT_WORKERS: usize = 10;
+
+/// How long to wait for a daemon that was just started to take connections.
+const DAEMON_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What ctm was started to do.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    /// Show the tasks of the daemon, starting one if there is none.
+    Attach,
+    /// Be the daemon, run with `--daemon`.
+    Daemon,
+    /// Stop the daemon and every task of it, run with `--stop`.
+    Stop,
+}
+
+/// Reads the mode and the worker count from the command line. The worker
+/// count is given by `--workers <n>` (or `-w <n>`), `None` if it is not.
+fn parse_args() -> Result<(Mode, Option<usize>), String> {
+    let mut args = std::env::args().skip(1);
+    let mut mode = Mode::Attach;
+    let mut workers = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-w" | "--workers" => {
+                let n = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("{arg} expects a positive number"))?;
+                workers = Some(n);
+            }
+            "--daemon" => mode = Mode::Daemon,
+            "--stop" => mode = Mode::Stop,
+            _ => return Err(format!("unknown argument '{arg}'")),
+        }
+    }
+    Ok((mode, workers))
+}
+
+/// Unix socket the daemon listens on. There is one daemon per project,
+/// and one per user for everything outside of a project, whose socket is
+/// in `$XDG_RUNTIME_DIR`, or else in a directory in /tmp that only the
+/// user may enter.
+fn socket_path() -> io::Result<PathBuf> {
+    if let Ok(root) = get_project_root() {
+        return Ok(root.join(".ctm.sock"));
+    }
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        if dir.is_absolute() {
+            return Ok(dir.join("ctm.sock"));
+        }
+    }
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() };
+    let dir = env::temp_dir().join(format!("ctm-{uid}"));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+        _ => {}
+    }
+    // anyone could have created it first, so it is only used if it is ours
+    // and closed to everyone else
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory only you may enter", dir.display()),
+        ));
+    }
+    Ok(dir.join("ctm.sock"))
+}
+
+/// Where the daemon keeps the output logs and the journal: in the project
+/// root, or outside of a project in `$XDG_STATE_HOME/ctm`, which is
+/// `~/.local/state/ctm` unless set. `None` if there is no home either.
+fn state_paths() -> Option<(PathBuf, PathBuf)> {
+    if let Ok(root) = get_project_root() {
+        return Some((root.join(".ctm_logs"), root.join(".ctm_journal")));
+    }
+    // relative paths are to be ignored, as they would depend on where ctm
+    // happens to be started from
+    let dir = match env::var_os("XDG_STATE_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    let dir = dir.join("ctm");
+    Some((dir.join("logs"), dir.join("journal")))
+}
+
+/// Runs the daemon until it is asked to stop: the tasks, the workers
+/// running them, and the socket clients attach to.
+fn run_daemon(socket: &Path, workers: usize) -> Result<(), Box<dyn std::error::Error>> {
+    // taken first, so that a second daemon leaves the journal alone
+    let listener = bind_socket(socket)?;
+
+    // database of commands, restored from where the last run left off
+    let tasks = Tasks::default();
+    if let Some((logs, journal)) = state_paths() {
+        if let Some(dir) = journal.parent() {
+            // the output of the commands is nobody else's business
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        tasks.set_log_dir(logs)?;
+        tasks.open_journal(&journal)?;
+    }
+
+    // start workers
+    spawn_threads(workers, tasks.clone());
+
+    serve(listener, tasks)?;
+    let _ = fs::remove_file(socket);
+    Ok(())
+}
+
+/// Connects to the daemon listening on `socket`, starting one in the
+/// background if there is none. `workers` resizes the worker pool of one
+/// that was running already.
+fn attach(socket: &Path, workers: Option<usize>) -> Result<Client, Box<dyn std::error::Error>> {
+    if let Ok(client) = Client::connect(socket) {
+        if let Some(workers) = workers {
+            client.set_workers(workers);
+        }
+        return Ok(client);
+    }
+
+    let mut daemon = Command::new(env::current_exe()?);
+    daemon
+        .arg("--daemon")
+        .args(["--workers", &workers.unwrap_or(DEFAULT_WORKERS).to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // a session of its own keeps the daemon, and with it the tasks, running
+    // once the terminal is closed
+    // SAFETY: setsid is async-signal-safe
+    unsafe {
+        daemon.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut daemon = daemon.spawn()?;
+
+    let started = Instant::now();
+    loop {
+        match Client::connect(socket) {
+            Ok(client) => return Ok(client),
+            Err(err) => {
+                if let Some(status) = daemon.try_wait()? {
+                    return Err(format!("the ctm daemon exited right away ({status})").into());
+                }
+                if started.elapsed() >= DAEMON_STARTUP_TIMEOUT {
+                    return Err(format!("could not connect to the ctm daemon: {err}").into());
+                }
+            }
+        }
+        sleep(Duration::from_millis(50));
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum MenuItem {
     Running,
     Finished,
+    Recurring,
     Stats,
     Map,
     Help,
@@ -47,9 +221,10 @@ impl From<MenuItem> for usize {
         match input {
             MenuItem::Running => 0,
             MenuItem::Finished => 1,
-            MenuItem::Stats => 2,
-            MenuItem::Map => 3,
-            MenuItem::Help => 4,
+            MenuItem::Recurring => 2,
+            MenuItem::Stats => 3,
+            MenuItem::Map => 4,
+            MenuItem::Help => 5,
         }
     }
 }
@@ -59,33 +234,76 @@ enum UIEvent<I> {
     Tick,
 }
 
+/// The output window as last shown, so that output is only read back from
+/// the logs and its colours parsed again once any of it changed.
+#[derive(Default)]
+struct OutputCache {
+    key: String,
+    text: Text<'static>,
+}
+
 /// Returns the three widgets required for the main page:
 /// 1. A command list that contains all the running or finished commands.
 /// 2. A status window that shows the running time of the selected command.
 /// 3. An output window showing the output of the selected command.
 ///
+/// When `follow` is set to the area of the output window, the output is
+/// scrolled so that its newest lines stay in view as they arrive. With
+/// `details` set, the output window shows how the command was set up to
+/// run instead of its output. Only the last `window` bytes of each output
+/// stream are shown, kept in `cache` for as long as they stay the same.
+///
 /// Returns:
 ///     List      - Command list
 ///     Table     - Status window
 ///     Paragraph - Command output
 fn running<'a>(
-    task_list: Arc<Mutex<HashMap<usize, Task>>>,
+    task_list: Arc<Mutex<BTreeMap<usize, Task>>>,
     cmd_list_state: &ListState,
-    scroll: &u16,
+    scroll: &mut u16,
+    follow: Option<Rect>,
+    details: bool,
+    window: usize,
+    cache: &mut OutputCache,
 ) -> (List<'a>, Table<'a>, Paragraph<'a>) {
-    let cmd_list: Vec<(usize, String)> = task_list
+    let cmd_list: Vec<(usize, String, Color, i32, Option<String>)> = task_list
         .lock()
         .unwrap()
         .iter()
-        .map(|task| (*task.0, task.1.command.clone()))
+        .map(|task| {
+            (
+                *task.0,
+                task.1.spec.command.clone(),
+                status_color(&task.1.status),
+                task.1.spec.priority,
+                match task.1.status {
+                    CommandStatus::Scheduled(start_at) => Some(countdown(start_at)),
+                    _ => None,
+                },
+            )
+        })
         .collect();
     let items: Vec<_> = cmd_list
         .iter()
-        .map(|(_id, cmd)| {
-            ListItem::new(Spans::from(vec![Span::styled(
-                cmd.clone(),
-                Style::default(),
-            )]))
+        .map(|(id, cmd, color, priority, countdown)| {
+            let mut item = vec![Span::styled(
+                format!("#{id} "),
+                Style::default().fg(Color::DarkGray),
+            )];
+            if let Some(countdown) = countdown {
+                item.push(Span::styled(
+                    format!("[in {countdown}] "),
+                    Style::default().fg(Color::LightBlue),
+                ));
+            }
+            if *priority != 0 {
+                item.push(Span::styled(
+                    format!("[{priority:+}] "),
+                    Style::default().fg(Color::Cyan),
+                ));
+            }
+            item.push(Span::styled(cmd.clone(), Style::default().fg(*color)));
+            ListItem::new(Spans::from(item))
         })
         .collect();
 
@@ -97,8 +315,8 @@ fn running<'a>(
                         .selected()
                         .expect("There is always a selected command."),
                 )
-                .expect("No selected command")
-                .clone(),
+                .map(|(id, cmd, _color, _priority, _start_at)| (*id, cmd.clone()))
+                .expect("No selected command"),
         )
     } else {
         None
@@ -139,32 +357,41 @@ fn running<'a>(
         Cell::from(Span::raw(match &selected_cmd {
             None => String::new(),
             Some(selected) => {
-                let start_time = task_list
-                    .lock()
-                    .unwrap()
-                    .get(&selected.0)
-                    .unwrap()
-                    .start_time;
-                match task_list
-                    .lock()
-                    .unwrap()
-                    .get(&selected.0)
-                    .unwrap()
-                    .finish_time
-                {
+                let task_list = task_list.lock().unwrap();
+                let task = task_list.get(&selected.0).unwrap();
+                match task.finish_time {
                     None => "n/a".to_string(),
                     Some(finish_time) => {
-                        let execution_time = finish_time.signed_duration_since(start_time);
-                        format!(
-                            "{}h {}m {}s",
-                            execution_time.num_hours(),
-                            execution_time.num_minutes(),
-                            execution_time.num_seconds()
-                        )
+                        // time spent paused does not count
+                        let execution_time = finish_time
+                            .signed_duration_since(task.start_time)
+                            .to_std()
+                            .unwrap_or_default()
+                            .saturating_sub(task.paused_time);
+                        format_duration(execution_time)
                     }
                 }
             }
         })),
+        Cell::from(Span::raw(match &selected_cmd {
+            None => String::new(),
+            Some(selected) => {
+                let task_list = task_list.lock().unwrap();
+                let task = task_list.get(&selected.0).unwrap();
+                match task.status {
+                    CommandStatus::InQueue if !task.spec.dependencies.is_empty() => {
+                        let dependencies: Vec<_> = task
+                            .spec
+                            .dependencies
+                            .iter()
+                            .map(|dependency| dependency.to_string())
+                            .collect();
+                        format!("Waiting for {}", dependencies.join(", "))
+                    }
+                    _ => task.status.to_string(),
+                }
+            }
+        })),
         Cell::from(Span::raw(match &selected_cmd {
             None => String::new(),
             Some(selected) => task_list
@@ -172,9 +399,80 @@ fn running<'a>(
                 .unwrap()
                 .get(&selected.0)
                 .unwrap()
-                .status
+                .spec
+                .exec_mode
                 .to_string(),
         })),
+        Cell::from(Span::raw(match &selected_cmd {
+            None => String::new(),
+            Some(selected) => {
+                let task_list = task_list.lock().unwrap();
+                let task = task_list.get(&selected.0).unwrap();
+                // tasks that ran into a limit still exited in some way
+                let exited = match (&task.status, task.exit_status) {
+                    (CommandStatus::LimitExceeded(_), Some(exit_status)) => {
+                        Some(CommandStatus::from_exit_status(exit_status))
+                    }
+                    _ => None,
+                };
+                match exited.as_ref().unwrap_or(&task.status) {
+                    CommandStatus::Succeeded => "0".to_string(),
+                    CommandStatus::Exited(code) => code.to_string(),
+                    CommandStatus::Signalled {
+                        signal,
+                        core_dumped,
+                    } => {
+                        let core = if *core_dumped { " (core)" } else { "" };
+                        format!("{}{core}", signal_name(*signal))
+                    }
+                    _ => "n/a".to_string(),
+                }
+            }
+        })),
+        Cell::from(Span::raw(match &selected_cmd {
+            None => String::new(),
+            Some(selected) => {
+                let task_list = task_list.lock().unwrap();
+                let task = task_list.get(&selected.0).unwrap();
+                format!("{}/{}", task.attempt(), task.spec.retry.max_attempts)
+            }
+        })),
+        Cell::from(Span::raw(match &selected_cmd {
+            None => String::new(),
+            Some(selected) => match task_list.lock().unwrap().get(&selected.0).unwrap().pid {
+                Some(pid) => pid.to_string(),
+                None => "n/a".to_string(),
+            },
+        })),
+        Cell::from(Span::raw(match &selected_cmd {
+            None => String::new(),
+            Some(selected) => match current_usage(&task_list, selected.0) {
+                Some(usage) => format!("{:.0}%", usage.cpu),
+                None => "n/a".to_string(),
+            },
+        })),
+        Cell::from(Span::raw(match &selected_cmd {
+            None => String::new(),
+            Some(selected) => match current_usage(&task_list, selected.0) {
+                Some(usage) => ByteSize(usage.memory).to_string(),
+                None => {
+                    // what a task that is done took up at most
+                    let task_list = task_list.lock().unwrap();
+                    let task = task_list.get(&selected.0).unwrap();
+                    match task.usage.iter().map(|usage| usage.memory).max() {
+                        Some(peak) => format!("{} peak", ByteSize(peak)),
+                        None => "n/a".to_string(),
+                    }
+                }
+            },
+        })),
+        Cell::from(Span::raw(match &selected_cmd {
+            None => String::new(),
+            Some(selected) => match current_usage(&task_list, selected.0) {
+                Some(usage) => format!("{} ({} proc)", usage.threads, usage.processes),
+                None => "n/a".to_string(),
+            },
+        })),
     ])])
     .header(Row::new(vec![
         Cell::from(Span::styled(
@@ -193,6 +491,34 @@ fn running<'a>(
             "Status",
             Style::default().add_modifier(Modifier::BOLD),
         )),
+        Cell::from(Span::styled(
+            "Shell",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Cell::from(Span::styled(
+            "Exit Code",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Cell::from(Span::styled(
+            "Attempt",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Cell::from(Span::styled(
+            "PID",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Cell::from(Span::styled(
+            "CPU",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Cell::from(Span::styled(
+            "Memory",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Cell::from(Span::styled(
+            "Threads",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
     ]))
     .block(
         Block::default()
@@ -202,46 +528,57 @@ fn running<'a>(
             .border_type(BorderType::Plain),
     )
     .widths(&[
-        Constraint::Percentage(40),
-        Constraint::Percentage(30),
-        Constraint::Percentage(30),
+        Constraint::Percentage(14),
+        Constraint::Percentage(8),
+        Constraint::Percentage(9),
+        Constraint::Percentage(15),
+        Constraint::Percentage(7),
+        Constraint::Percentage(8),
+        Constraint::Percentage(6),
+        Constraint::Percentage(7),
+        Constraint::Percentage(6),
+        Constraint::Percentage(11),
+        Constraint::Percentage(9),
     ]);
 
-    // The code breaks if refactored as clippy suggested.
-    // It has to be this way.
-    #[allow(clippy::collapsible_else_if, clippy::unnecessary_unwrap)]
-    let exe_res = if selected_cmd.is_none() {
-        String::new()
-    } else {
-        if let Some(output) = &task_list
-            .lock()
-            .unwrap()
-            .get(&selected_cmd.unwrap().0)
-            .unwrap()
-            .output
-        {
-            let out = match String::from_utf8_lossy(&output.stdout) {
-                std::borrow::Cow::Borrowed(out) => format!("{}\n", out),
-                std::borrow::Cow::Owned(_) => String::new(),
-            };
-            let err = match String::from_utf8_lossy(&output.stderr) {
-                std::borrow::Cow::Borrowed(out) => format!("{}\n", out),
-                std::borrow::Cow::Owned(_) => String::new(),
-            };
-            format!("{}\n\n{}", out, err)
-        } else {
-            String::new()
+    // commands running under a terminal tend to print in colour
+    let exe_res = match &selected_cmd {
+        None => Text::default(),
+        Some(selected) if details => {
+            ansi::to_text(&task_details(&task_list.lock().unwrap()[&selected.0]))
+        }
+        Some(selected) => {
+            let task_list = task_list.lock().unwrap();
+            let task = task_list.get(&selected.0).unwrap();
+            let key = output_key(selected.0, task, window);
+            if cache.key != key {
+                cache.text = ansi::to_text(&output_text(task, window));
+                cache.key = key;
+            }
+            cache.text.clone()
         }
     };
-
-    let line_count = exe_res.lines().count() as u16;
-    let scroll = *min(scroll, &line_count);
+    let follow = follow.filter(|_| !details);
+    let line_count = match follow {
+        Some(area) => wrapped_line_count(&exe_res, area.width.saturating_sub(2)),
+        None => exe_res.height() as u16,
+    };
+    if let Some(area) = follow {
+        // keep the last line at the bottom of the output window
+        *scroll = line_count.saturating_sub(area.height.saturating_sub(2));
+    }
+    let scroll = *min(&*scroll, &line_count);
+    let written = selected_cmd
+        .as_ref()
+        .and_then(|selected| task_list.lock().unwrap().get(&selected.0).map(output_size))
+        .filter(|written| *written > 0);
+    let title = match (details, written) {
+        (true, _) => "Command Details".to_string(),
+        (false, Some(written)) => format!("Command Output - {} written", ByteSize::b(written)),
+        (false, None) => "Command Output".to_string(),
+    };
     let output_display = Paragraph::new(exe_res)
-        .block(
-            Block::default()
-                .title("Command Output")
-                .borders(Borders::ALL),
-        )
+        .block(Block::default().title(title).borders(Borders::ALL))
         .style(Style::default().fg(Color::White).bg(Color::Black))
         .scroll((scroll, 0))
         .alignment(Alignment::Left)
@@ -250,10 +587,460 @@ fn running<'a>(
     (list, cmd_stats, output_display)
 }
 
-#[derive(PartialEq)]
+/// The output of every attempt of a task, each under a header saying how
+/// it went, the last `window` bytes of each stream.
+fn output_text(task: &Task, window: usize) -> String {
+    let max_attempts = task.spec.retry.max_attempts;
+    let mut text = String::new();
+    for (i, attempt) in task.attempts.iter().enumerate() {
+        text.push_str(&format!(
+            "--- attempt {}/{max_attempts}: {} ---\n{}\n\n\n{}\n",
+            i + 1,
+            attempt.status,
+            stream_text(&attempt.output.stdout, window),
+            stream_text(&attempt.output.stderr, window)
+        ));
+    }
+    if !task.attempts.is_empty() {
+        text.push_str(&format!(
+            "--- attempt {}/{max_attempts}: {} ---\n",
+            task.attempt(),
+            task.status
+        ));
+    }
+    match &task.status {
+        CommandStatus::Failed(err) => text.push_str(&format!("Could not run command: {err}\n")),
+        _ => {
+            let output = task.output.lock().unwrap();
+            text.push_str(&format!(
+                "{}\n\n\n{}\n",
+                stream_text(&output.stdout, window),
+                stream_text(&output.stderr, window)
+            ));
+        }
+    }
+    text
+}
+
+/// Tells whether the output window would show anything different from
+/// when the key was taken, without reading any of the output.
+fn output_key(id: usize, task: &Task, window: usize) -> String {
+    let mut key = format!("{id} {window} {} {}", task.attempts.len(), task.status);
+    let output = task.output.lock().unwrap();
+    let attempts = task.attempts.iter().map(|attempt| &attempt.output);
+    for output in attempts.chain([&*output]) {
+        for stream in [&output.stdout, &output.stderr] {
+            key.push_str(&format!(
+                " {} {} {} {}",
+                stream.len(),
+                stream.discarded_front(),
+                stream.discarded_back(),
+                stream.log().is_some()
+            ));
+        }
+    }
+    key
+}
+
+/// The last `window` bytes of an output stream, between notes on what
+/// else it wrote: output discarded because of its cap, and earlier output
+/// that is only in the log file.
+fn stream_text(stream: &OutputStream, window: usize) -> String {
+    let shown = stream.last(window);
+    let mut text = String::new();
+    if stream.discarded_front() > 0 {
+        text.push_str(&format!(
+            "[{} discarded, only the last {} is kept]\n",
+            ByteSize::b(stream.discarded_front()),
+            ByteSize::b(stream.cap().map_or(0, |cap| cap.limit)),
+        ));
+    }
+    let earlier = stream.len() - stream.discarded_front() - stream.discarded_back();
+    let earlier = earlier - shown.len() as u64;
+    if earlier > 0 {
+        let log = match stream.log() {
+            Some(log) => format!(" in {}", log.display()),
+            None => String::new(),
+        };
+        text.push_str(&format!(
+            "[{} earlier{log}, scroll up past the top to load more]\n",
+            ByteSize::b(earlier)
+        ));
+    }
+    text.push_str(&String::from_utf8_lossy(&shown));
+    if stream.discarded_back() > 0 {
+        let stopped = if stream.exceeded() {
+            ", the command was stopped"
+        } else {
+            ""
+        };
+        text.push_str(&format!(
+            "\n[{} more discarded after the first {}{stopped}]\n",
+            ByteSize::b(stream.discarded_back()),
+            ByteSize::b(stream.cap().map_or(0, |cap| cap.limit)),
+        ));
+    }
+    text
+}
+
+/// Bytes a task wrote to stdout and stderr in total, in its current attempt.
+fn output_size(task: &Task) -> u64 {
+    let output = task.output.lock().unwrap();
+    output.stdout.len() + output.stderr.len()
+}
+
+/// Latest resource usage of a task whose processes are still running.
+fn current_usage(task_list: &Mutex<BTreeMap<usize, Task>>, id: usize) -> Option<Usage> {
+    let task_list = task_list.lock().unwrap();
+    let task = task_list.get(&id)?;
+    match task.status {
+        CommandStatus::Running | CommandStatus::Paused => task.usage.back().copied(),
+        _ => None,
+    }
+}
+
+/// Draws the CPU and memory use of the selected task over time, side by side.
+fn render_usage<B: Backend>(
+    f: &mut Frame<B>,
+    area: Rect,
+    task_list: &Mutex<BTreeMap<usize, Task>>,
+    id: Option<usize>,
+) {
+    let (cpu, memory): (Vec<u64>, Vec<u64>) = match id.and_then(|id| {
+        let task_list = task_list.lock().unwrap();
+        task_list.get(&id).map(|task| task.usage.clone())
+    }) {
+        Some(usage) => usage
+            .iter()
+            .map(|usage| (usage.cpu.round() as u64, usage.memory))
+            .unzip(),
+        None => (vec![], vec![]),
+    };
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(area);
+    // newest samples on the right
+    let visible = |data: &[u64], area: Rect| {
+        let width = area.width.saturating_sub(2) as usize;
+        data[data.len().saturating_sub(width)..].to_vec()
+    };
+
+    let cpu = visible(&cpu, chunks[0]);
+    let cpu_sparkline = Sparkline::default()
+        .block(Block::default().title("CPU").borders(Borders::ALL))
+        .data(&cpu)
+        .max(cpu.iter().copied().max().unwrap_or(0).max(100))
+        .style(Style::default().fg(Color::Green));
+    f.render_widget(cpu_sparkline, chunks[0]);
+
+    let memory = visible(&memory, chunks[1]);
+    let memory_sparkline = Sparkline::default()
+        .block(Block::default().title("Memory").borders(Borders::ALL))
+        .data(&memory)
+        .style(Style::default().fg(Color::Cyan));
+    f.render_widget(memory_sparkline, chunks[1]);
+}
+
+/// Everything that was set up for a task to run with, one setting per line.
+fn task_details(task: &Task) -> String {
+    let spec = &task.spec;
+    let mut details = format!("Command: {}\n", spec.command);
+    details.push_str(&format!("Shell: {}\n", spec.exec_mode));
+    let terminal = if spec.pty { "pseudo-terminal" } else { "none" };
+    details.push_str(&format!("Terminal: {terminal}\n"));
+    let cwd = match &spec.cwd {
+        Some(cwd) => cwd.display().to_string(),
+        None => "(inherited)".to_string(),
+    };
+    details.push_str(&format!("Working directory: {cwd}\n"));
+    if let Some(env_file) = &spec.env_file {
+        details.push_str(&format!("Env file: {}\n", env_file.display()));
+    }
+    if spec.env.is_empty() {
+        details.push_str("Environment: (inherited)\n");
+    } else {
+        details.push_str("Environment:\n");
+        for (var, value) in &spec.env {
+            match value {
+                Some(value) => details.push_str(&format!("    {var}={value}\n")),
+                None => details.push_str(&format!("    unset {var}\n")),
+            }
+        }
+    }
+    let timeout = match spec.timeout {
+        Some(timeout) => format_duration(timeout),
+        None => "none".to_string(),
+    };
+    details.push_str(&format!("Timeout: {timeout}\n"));
+    details.push_str(&format!(
+        "Attempts: {} (backoff {})\n",
+        spec.retry.max_attempts, spec.retry.backoff
+    ));
+    details.push_str(&format!("Priority: {}\n", spec.priority));
+    if let Some(group) = &spec.group {
+        details.push_str(&format!("Group: {group}\n"));
+    }
+    if let Some(cap) = spec.stream_cap {
+        details.push_str(&format!("Output cap: {cap} per stream\n"));
+    }
+    if let Some(start_at) = spec.start_at {
+        details.push_str(&format!(
+            "Start at: {}\n",
+            start_at.format("%Y-%m-%d %H:%M:%S")
+        ));
+    }
+    if let Some(parent) = task.parent {
+        details.push_str(&format!("Run of: @{parent} (overlap {})\n", spec.overlap));
+    }
+    details.push_str(&format!("Limits: {}\n", spec.limits));
+    if let Some(nice) = spec.nice {
+        details.push_str(&format!("Nice: {nice}\n"));
+    }
+    if !spec.dependencies.is_empty() {
+        let dependencies: Vec<_> = spec
+            .dependencies
+            .iter()
+            .map(|dependency| dependency.to_string())
+            .collect();
+        details.push_str(&format!("After: {}\n", dependencies.join(", ")));
+    }
+    details
+}
+
+/// Returns the widgets of the cron page:
+/// 1. A list of the recurring commands.
+/// 2. A table describing the selected one.
+/// 3. A table of the runs it created, newest first.
+fn recurring<'a>(client: &Client, state: &ListState) -> (List<'a>, Table<'a>, Table<'a>) {
+    let recurring = client.get_recurring();
+    let recurring = recurring.lock().unwrap();
+    let items: Vec<_> = recurring
+        .iter()
+        .map(|(id, recurring)| {
+            ListItem::new(Spans::from(vec![
+                Span::styled(format!("@{id} "), Style::default().fg(Color::DarkGray)),
+                Span::raw(recurring.spec.command.clone()),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .title("Recurring")
+                .border_type(BorderType::Plain),
+        )
+        .highlight_style(
+            Style::default()
+                .bg(Color::Yellow)
+                .fg(Color::Black)
+                .add_modifier(Modifier::BOLD),
+        );
+
+    let selected = state
+        .selected()
+        .and_then(|selected| recurring.values().nth(selected));
+    let header = |titles: &[&'static str]| {
+        Row::new(
+            titles
+                .iter()
+                .map(|title| {
+                    Cell::from(Span::styled(
+                        *title,
+                        Style::default().add_modifier(Modifier::BOLD),
+                    ))
+                })
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    let rows = match selected {
+        None => vec![],
+        Some(recurring) => {
+            let next_run = match (recurring.pending, recurring.next_run) {
+                (true, _) => "after the current run".to_string(),
+                (false, Some(next_run)) => format!("in {}", countdown(next_run)),
+                (false, None) => "never".to_string(),
+            };
+            vec![Row::new(vec![
+                Cell::from(recurring.spec.command.clone()),
+                Cell::from(
+                    recurring
+                        .spec
+                        .recurrence
+                        .as_ref()
+                        .map_or(String::new(), |recurrence| recurrence.to_string()),
+                ),
+                Cell::from(recurring.spec.overlap.to_string()),
+                Cell::from(next_run),
+                Cell::from(recurring.runs.len().to_string()),
+                Cell::from(recurring.skipped.to_string()),
+            ])]
+        }
+    };
+    let stat = Table::new(rows)
+        .header(header(&[
+            "Command", "Schedule", "Overlap", "Next Run", "Runs", "Skipped",
+        ]))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .title("Detail")
+                .border_type(BorderType::Plain),
+        )
+        .widths(&[
+            Constraint::Percentage(30),
+            Constraint::Percentage(20),
+            Constraint::Percentage(10),
+            Constraint::Percentage(20),
+            Constraint::Percentage(10),
+            Constraint::Percentage(10),
+        ]);
+
+    let runs: Vec<_> = match selected {
+        None => vec![],
+        Some(recurring) => {
+            let running = client.get_currently_running();
+            let running = running.lock().unwrap();
+            let finished = client.get_finished();
+            let finished = finished.lock().unwrap();
+            recurring
+                .runs
+                .iter()
+                .rev()
+                .filter_map(|id| {
+                    let task = running.get(id).or_else(|| finished.get(id))?;
+                    let execution_time = match task.finish_time {
+                        None => "n/a".to_string(),
+                        Some(finish_time) => format_duration(
+                            finish_time
+                                .signed_duration_since(task.start_time)
+                                .to_std()
+                                .unwrap_or_default()
+                                .saturating_sub(task.paused_time),
+                        ),
+                    };
+                    Some(Row::new(vec![
+                        Cell::from(format!("#{id}")),
+                        Cell::from(task.start_time.format("%Y-%m-%d %H:%M:%S").to_string()),
+                        Cell::from(execution_time),
+                        Cell::from(Span::styled(
+                            task.status.to_string(),
+                            Style::default().fg(status_color(&task.status)),
+                        )),
+                    ]))
+                })
+                .collect()
+        }
+    };
+    let runs = Table::new(runs)
+        .header(header(&["Run", "Start Time", "Execution Time", "Status"]))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .title("Past Runs")
+                .border_type(BorderType::Plain),
+        )
+        .widths(&[
+            Constraint::Percentage(10),
+            Constraint::Percentage(25),
+            Constraint::Percentage(20),
+            Constraint::Percentage(45),
+        ]);
+
+    (list, stat, runs)
+}
+
+/// Time left until `start_at`, in whole seconds rounded up so that it never
+/// shows 0s too early.
+fn countdown(start_at: DateTime<Local>) -> String {
+    let remaining = (start_at - Local::now()).num_milliseconds().max(0) as u64;
+    format_duration(Duration::from_secs(remaining.div_ceil(1000)))
+}
+
+/// Colour of a command in the command lists, depending on how it is doing.
+fn status_color(status: &CommandStatus) -> Color {
+    match status {
+        CommandStatus::Scheduled(_) => Color::LightBlue,
+        CommandStatus::InQueue => Color::Gray,
+        CommandStatus::Running => Color::White,
+        CommandStatus::Paused => Color::Blue,
+        CommandStatus::Retrying { .. } => Color::LightYellow,
+        CommandStatus::Succeeded => Color::Green,
+        CommandStatus::Exited(_) | CommandStatus::Failed(_) => Color::Red,
+        CommandStatus::Signalled { .. } => Color::Magenta,
+        CommandStatus::Cancelled | CommandStatus::Skipped(_) => Color::DarkGray,
+        CommandStatus::Interrupted => Color::Yellow,
+        CommandStatus::TimedOut(_) | CommandStatus::LimitExceeded(_) => Color::LightRed,
+    }
+}
+
+/// Number of rows `text` takes up when wrapped to `width` columns.
+fn wrapped_line_count(text: &Text, width: u16) -> u16 {
+    let width = max(width, 1) as usize;
+    let rows: usize = text
+        .lines
+        .iter()
+        .map(|line| max(1, line.width().div_ceil(width)))
+        .sum();
+    min(rows, u16::MAX as usize) as u16
+}
+
+/// Bytes a terminal sends for a key press, `None` for keys that are not
+/// passed on to attached tasks.
+fn key_input(event: KeyEvent) -> Option<Vec<u8>> {
+    let input: &[u8] = match event.code {
+        KeyCode::Char(c) if event.modifiers.contains(KeyModifiers::CONTROL) => {
+            if !c.is_ascii_alphabetic() {
+                return None;
+            }
+            return Some(vec![c.to_ascii_lowercase() as u8 & 0x1f]);
+        }
+        KeyCode::Char(c) => return Some(c.to_string().into_bytes()),
+        KeyCode::Enter => b"\r",
+        KeyCode::Backspace => b"\x7f",
+        KeyCode::Tab => b"\t",
+        KeyCode::BackTab => b"\x1b[Z",
+        KeyCode::Up => b"\x1b[A",
+        KeyCode::Down => b"\x1b[B",
+        KeyCode::Right => b"\x1b[C",
+        KeyCode::Left => b"\x1b[D",
+        KeyCode::Home => b"\x1b[H",
+        KeyCode::End => b"\x1b[F",
+        KeyCode::Delete => b"\x1b[3~",
+        KeyCode::PageUp => b"\x1b[5~",
+        KeyCode::PageDown => b"\x1b[6~",
+        _ => return None,
+    };
+    Some(input.to_vec())
+}
+
+/// Id of the task selected in a command list.
+fn selected_id(state: &ListState, task_list: &Mutex<BTreeMap<usize, Task>>) -> Option<usize> {
+    let selected = state.selected()?;
+    task_list.lock().unwrap().keys().nth(selected).copied()
+}
+
+/// Moves the selection of a list back onto its last entry after entries
+/// have been removed from under it.
+fn clamp_selection(state: &mut ListState, len: usize) {
+    if let Some(selected) = state.selected() {
+        if selected >= len {
+            state.select(Some(len.saturating_sub(1)));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
 enum InputMode {
     Normal,
     Command,
+    /// Keys are sent to the task with this id.
+    Attached(usize),
 }
 
 impl fmt::Display for InputMode {
@@ -261,6 +1048,7 @@ impl fmt::Display for InputMode {
         let res = match *self {
             InputMode::Normal => "Normal",
             InputMode::Command => "Command",
+            InputMode::Attached(id) => return write!(f, "Attached to #{id}"),
         };
         write!(f, "{res}")
     }
@@ -294,7 +1082,34 @@ async fn perf(sender: Sender<PerfData>, interval: Duration) {
     }
 }
 
+/// Title of the menu block, showing how busy the worker pool is.
+fn menu_title(workers: Workers) -> String {
+    let mut title = format!("Menu - Workers: {}/{} busy", workers.busy, workers.limit);
+    if workers.alive > workers.limit {
+        title += &format!(" ({} finishing)", workers.alive - workers.limit);
+    }
+    title
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (mode, workers) = parse_args()?;
+    let socket = socket_path()?;
+    match mode {
+        Mode::Daemon => return run_daemon(&socket, workers.unwrap_or(DEFAULT_WORKERS)),
+        Mode::Stop => {
+            let Ok(client) = Client::connect(&socket) else {
+                println!("No ctm daemon is running.");
+                return Ok(());
+            };
+            stop_daemon(&client);
+            return Ok(());
+        }
+        Mode::Attach => {}
+    }
+
+    // the tasks run in the daemon, which is left running on exit
+    let client = attach(&socket, workers)?;
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -303,13 +1118,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut terminal = Terminal::new(backend)?;
     terminal.clear()?;
 
-    // database of commands
-    let tasks = Tasks::default();
-    let mut command_id: usize = 0;
-
-    // start workers
-    spawn_threads(10, tasks.clone());
-
     // Handles user input in a different thread and sends them through a channel.
     let (tx, rx) = channel();
     let tick_rate = Duration::from_millis(200);
@@ -331,7 +1139,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     // Add the different pages and select "Running" as the default active one.
-    let menu_titles = vec!["Running", "Finished", "Stats", "Map", "Help"];
+    let menu_titles = ["Running", "Finished", "Cron", "Stats", "Map", "Help"];
     let mut active_menu_item = MenuItem::Running;
 
     // state of the currently running command list in the main page
@@ -341,7 +1149,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // state of the finished command list in the main page
     let mut finished_list_state = ListState::default();
     finished_list_state.select(Some(0));
+
+    // state of the recurring command list in the cron page
+    let mut recurring_list_state = ListState::default();
+    recurring_list_state.select(Some(0));
     let mut scroll = 0;
+    // keep the output of running commands scrolled to the end
+    let mut follow = true;
+    // whether the output window shows the selected command's setup instead
+    let mut details = false;
+    // bytes of each output stream shown, more are loaded from the log files
+    // when scrolling up past the top
+    let mut output_window = OUTPUT_TAIL;
+    let mut output_cache = OutputCache::default();
 
     // Initialize command input prompt
     let mut input_mode = InputMode::Normal;
@@ -349,6 +1169,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut command_hist: Vec<String> = vec![];
     load_hist(&mut command_hist);
     let mut curr_hist_index: usize = command_hist.len();
+    let mut cli_error: Option<ParseError> = None;
+    // options applied to every submitted command, changed by typing directives alone
+    let mut defaults = TaskSpec::default();
+    // whether to stop the daemon on exit rather than leave it running
+    let mut stop = false;
 
     // Initialize system stats logging
     let log_length = 40;
@@ -365,7 +1190,27 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             mem_hist.push(stat.mem_usage);
         }
 
-        let currently_running = &tasks.get_currently_running();
+        let currently_running = &client.get_currently_running();
+        // tasks move between the lists on their own, keep the selections in range
+        clamp_selection(
+            &mut running_list_state,
+            currently_running.lock().unwrap().len(),
+        );
+        clamp_selection(
+            &mut finished_list_state,
+            client.get_finished().lock().unwrap().len(),
+        );
+        clamp_selection(
+            &mut recurring_list_state,
+            client.get_recurring().lock().unwrap().len(),
+        );
+        // finished tasks come without their output, which is asked for once
+        // one of them is looked at
+        if active_menu_item == MenuItem::Finished {
+            if let Some(id) = selected_id(&finished_list_state, &client.get_finished()) {
+                client.load_output(id);
+            }
+        }
         // renders UI
         terminal.draw(|f| {
             let chunks = Layout::default()
@@ -392,12 +1237,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             let menu = Tabs::new(menu_items)
                 .select(active_menu_item.into())
-                .block(Block::default().title("Menu").borders(Borders::ALL))
+                .block(
+                    Block::default()
+                        .title(menu_title(client.get_workers()))
+                        .borders(Borders::ALL),
+                )
                 .style(Style::default().fg(Color::White))
                 .highlight_style(Style::default().fg(Color::Yellow))
                 .divider(Span::raw("|"));
 
-            let cli_title = format!("Command Line - Input Mode: {input_mode}");
+            let mut cli_title = vec![Span::raw(format!(
+                "Command Line - Input Mode: {input_mode} - Shell: {}",
+                defaults.exec_mode
+            ))];
+            if let Some(err) = &cli_error {
+                cli_title.push(Span::styled(
+                    format!(" - Error: {err}"),
+                    Style::default().fg(Color::Red),
+                ));
+            }
+            let cli_title = Spans::from(cli_title);
             let cli = Paragraph::new(command_input.clone())
                 .block(Block::default().title(cli_title).borders(Borders::ALL))
                 .style(Style::default())
@@ -421,16 +1280,27 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             )
                             .split(main_chunks[0]);
                         {
-                            // Details and Command Output
+                            // Details, Usage and Command Output
                             let chunks = Layout::default()
                                 .direction(Direction::Vertical)
                                 .constraints(
-                                    [Constraint::Percentage(20), Constraint::Percentage(80)]
-                                        .as_ref(),
+                                    [
+                                        Constraint::Percentage(20),
+                                        Constraint::Length(5),
+                                        Constraint::Min(0),
+                                    ]
+                                    .as_ref(),
                                 )
                                 .split(middle_chunks[1]);
-                            let (cmd_list, stat, output) =
-                                running(currently_running.clone(), &running_list_state, &scroll);
+                            let (cmd_list, stat, output) = running(
+                                currently_running.clone(),
+                                &running_list_state,
+                                &mut scroll,
+                                follow.then_some(chunks[2]),
+                                details,
+                                output_window,
+                                &mut output_cache,
+                            );
 
                             f.render_stateful_widget(
                                 cmd_list,
@@ -438,7 +1308,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 &mut running_list_state,
                             );
                             f.render_widget(stat, chunks[0]);
-                            f.render_widget(output, chunks[1]);
+                            render_usage(
+                                f,
+                                chunks[1],
+                                currently_running,
+                                selected_id(&running_list_state, currently_running),
+                            );
+                            f.render_widget(output, chunks[2]);
                         }
                         f.render_widget(cli, main_chunks[1]);
                     }
@@ -452,15 +1328,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         )
                         .split(chunks[1]);
                     {
-                        // Details and Command Output
+                        // Details, Usage and Command Output
                         let chunks = Layout::default()
                             .direction(Direction::Vertical)
                             .constraints(
-                                [Constraint::Percentage(20), Constraint::Percentage(80)].as_ref(),
+                                [
+                                    Constraint::Percentage(20),
+                                    Constraint::Length(5),
+                                    Constraint::Min(0),
+                                ]
+                                .as_ref(),
                             )
                             .split(middle_chunks[1]);
-                        let (cmd_list, stat, output) =
-                            running(tasks.get_finished(), &finished_list_state, &scroll);
+                        let finished = client.get_finished();
+                        let (cmd_list, stat, output) = running(
+                            finished.clone(),
+                            &finished_list_state,
+                            &mut scroll,
+                            None,
+                            details,
+                            output_window,
+                            &mut output_cache,
+                        );
 
                         f.render_stateful_widget(
                             cmd_list,
@@ -468,9 +1357,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             &mut finished_list_state,
                         );
                         f.render_widget(stat, chunks[0]);
-                        f.render_widget(output, chunks[1]);
+                        render_usage(
+                            f,
+                            chunks[1],
+                            &finished,
+                            selected_id(&finished_list_state, &finished),
+                        );
+                        f.render_widget(output, chunks[2]);
                     }
                 }
+                MenuItem::Recurring => {
+                    let middle_chunks = Layout::default()
+                        .direction(Direction::Horizontal)
+                        .constraints(
+                            [Constraint::Percentage(20), Constraint::Percentage(80)].as_ref(),
+                        )
+                        .split(chunks[1]);
+                    // Details and Past Runs
+                    let right_chunks = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints(
+                            [Constraint::Percentage(20), Constraint::Percentage(80)].as_ref(),
+                        )
+                        .split(middle_chunks[1]);
+                    let (list, stat, runs) = recurring(&client, &recurring_list_state);
+
+                    f.render_stateful_widget(list, middle_chunks[0], &mut recurring_list_state);
+                    f.render_widget(stat, right_chunks[0]);
+                    f.render_widget(runs, right_chunks[1]);
+                }
                 MenuItem::Stats => {
                     let chunks = Layout::default()
                         .direction(Direction::Vertical)
@@ -593,22 +1508,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                     let cpu_chunks = Layout::default()
                         .direction(Direction::Vertical)
-                        .constraints(
-                            [
-                                Constraint::Length(3),
-                                Constraint::Min(10),
-                            ]
-                        )
+                        .constraints([Constraint::Length(3), Constraint::Min(10)])
                         .split(chunks[0]);
 
                     let mem_chunks = Layout::default()
                         .direction(Direction::Vertical)
-                        .constraints(
-                            [
-                                Constraint::Length(3),
-                                Constraint::Min(10),
-                            ]
-                        )
+                        .constraints([Constraint::Length(3), Constraint::Min(10)])
                         .split(chunks[2]);
 
                     f.render_widget(cpu_load, cpu_chunks[0]);
@@ -661,16 +1566,93 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                         While in 'Running' or 'Finished' tab, press 'Up' and 'Down' \
                         to select different entries. Or use 'j' and 'k' like in vim!
-                        While in 'Finished' tab, use 'PageUp' and 'PageDown' to scroll \
-                        through long outputs.
+                        Use 'PageUp' and 'PageDown' to scroll through long outputs. \
+                        Only the latest output is shown at first; the rest is kept in \
+                        `.ctm_logs`, and pressing 'PageUp' at the top loads more of it. \
+                        In 'Running' tab, the output of the selected command is shown \
+                        as it is printed and follows its end; press 't' to toggle \
+                        following. Press 'x' or 'Delete' to cancel the selected \
+                        command, running commands get 5 seconds to exit before \
+                        they are killed. Press 'z' to pause the selected command \
+                        and again to resume it, paused time does not count towards \
+                        its execution time or timeout.
                         While in 'Running' tab, press 'i' to go into command mode, \
                         this activates the command line input. While in command mode, \
                         use Up and Down to scroll through command history.
+                        Commands are split into words like in a shell: quotes, \
+                        backslash escapes, $VAR, ${VAR} and ~ all work as usual.
+                        Prefix a command with ':shell <shell>' (e.g. ':shell bash') \
+                        to run it through that shell so pipes and redirections work, \
+                        or with ':direct' to run it without one. Typing only the \
+                        prefix makes it the default for every following command.
+                        ':timeout <duration>' (e.g. ':timeout 10m make test') stops \
+                        a command that runs for longer than that, ':timeout off' \
+                        clears the default.
+                        ':retry <attempts>' runs a command that did not succeed again, \
+                        up to that many attempts in total. ':backoff fixed:5s' or \
+                        ':backoff exp:1s' sets the wait between attempts, and \
+                        ':retry-on 1,75' only retries on those exit codes.
+                        ':after 3' starts a command only once command #3 succeeded, \
+                        ':after-fail 3' once it failed and ':after-done 3' once it \
+                        finished either way. Separate several ids with commas. If \
+                        a command it waits for ends any other way, it is skipped.
+                        ':prio <n>' queues a command with priority n (default 0), \
+                        higher priorities start first. Press '+' or '-' in 'Running' \
+                        tab to change the priority of a command that is still queued.
+                        ':workers <n>' lets n commands run at once (10 by default, or \
+                        as given by '--workers <n>' on startup). Commands that are \
+                        already running are not stopped when lowering it.
+                        ':cd <dir>' runs a command in that directory, ':env VAR=value' \
+                        and ':unset VAR' change its environment, and ':envfile <file>' \
+                        loads variables from a file of VAR=value lines first \
+                        (':envfile off' clears the default). Press 'd' in 'Running' \
+                        or 'Finished' tab to see how the selected command was set up.
+                        Arguments of directives are quoted like words of a command, \
+                        as in ':env CFLAGS=\"-O2 -g\"', and ': cmd' is just a command.
+                        ':pty' runs a command under a pseudo-terminal, so it prints \
+                        colours and prompts as it would in a terminal; ':nopty' turns \
+                        that default off. Press 'a' in 'Running' tab to type into the \
+                        selected command, and 'ESC' to stop doing so.
+                        ':limit mem=512M,cpu=30s,nofile=64,core=0' limits the memory, \
+                        CPU time, open files and core dumps of a command's processes, \
+                        and ':nice <n>' runs them at that niceness. Use 'off' to lift \
+                        a default limit again, e.g. ':limit cpu=off' or ':limit off'.
+                        ':at 02:00' (or ':at 2024-05-01T02:00') queues a command at \
+                        that time, ':in 30m' once that much time has passed. The time \
+                        left is shown in the command list until then.
+                        ':every 5m <command>' runs a command every 5 minutes, starting \
+                        right away, and ':cron */5 * * * * <command>' whenever the \
+                        cron expression matches. Each run is a command of its own; \
+                        the 'Cron' tab lists them, and 'x' there stops the selected \
+                        command from recurring. ':overlap skip', 'queue' or 'allow' \
+                        decides what happens when a run is due while the previous \
+                        one is still going: leave it out (the default), start it \
+                        once the previous one is done, or start it anyway.
+                        ':group db=2' puts a command in the concurrency group 'db' and \
+                        lets at most 2 commands of it run at once, no matter how many \
+                        workers are idle; the others wait in the queue. ':group db' \
+                        joins a group without changing its limit, which is 1 unless \
+                        given, and ':group off' leaves a default group.
+                        ':output tail:10M' keeps only the last 10M of each of a \
+                        command's output streams, ':output head:10M' only the first, and \
+                        ':output kill:10M' stops the command once either stream got \
+                        more than that. The cap counts stdout and stderr separately, so \
+                        up to twice as much is kept in all. ':output off' lifts a default cap.
 
                         When you are finished, press 'ESC' to exit insert mode.
 
-                        Command history is persistent, stored in `.cmd_hist`.
-                        Press 'q' in Normal mode to exit the program safely.";
+                        Command history is persistent, stored in `.cmd_hist`. So are \
+                        the commands themselves, stored in `.ctm_journal`: finished \
+                        commands are listed again and queued ones queued again when \
+                        ctm starts, and commands it had to stop are 'Interrupted'. \
+                        Outside of a project, the journal and the logs are kept in \
+                        `$XDG_STATE_HOME/ctm` (by default `~/.local/state/ctm`).
+                        Commands run in a background daemon, one per project, which \
+                        ctm starts when there is none yet. Press 'q' in Normal mode to \
+                        detach from it: commands keep running, and running ctm again, \
+                        from any number of terminals, shows them again. Press 'Q' to \
+                        exit and stop the daemon with every command that is still \
+                        running, or run 'ctm --stop' to do so from the shell.";
 
                     let help = Paragraph::new(help_text)
                         .block(Block::default().title("Help Message").borders(Borders::ALL))
@@ -690,67 +1672,158 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             UIEvent::Input(event) => match input_mode {
                 InputMode::Normal => match event.code {
                     KeyCode::Char('q') => break,
+                    KeyCode::Char('Q') => {
+                        stop = true;
+                        break;
+                    }
                     KeyCode::Char('r') => active_menu_item = MenuItem::Running,
                     KeyCode::Char('f') => active_menu_item = MenuItem::Finished,
+                    KeyCode::Char('c') => active_menu_item = MenuItem::Recurring,
                     KeyCode::Char('s') => active_menu_item = MenuItem::Stats,
                     KeyCode::Char('m') => active_menu_item = MenuItem::Map,
                     KeyCode::Char('h') => active_menu_item = MenuItem::Help,
-                    KeyCode::PageUp => scroll = if scroll > 2 { scroll - 2 } else { 0 },
+                    KeyCode::PageUp => {
+                        follow = false;
+                        if scroll == 0 {
+                            output_window = output_window.saturating_add(OUTPUT_TAIL);
+                        }
+                        scroll = scroll.saturating_sub(2);
+                    }
                     KeyCode::PageDown => scroll = min(scroll + 2, u16::MAX),
-                    KeyCode::Up | KeyCode::Char('k') => {
-                        match active_menu_item {
-                            MenuItem::Running => {
-                                if let Some(selected) = running_list_state.selected() {
-                                    if selected > 0 {
-                                        running_list_state.select(Some(selected - 1));
-                                    }
+                    KeyCode::Up | KeyCode::Char('k') => match active_menu_item {
+                        MenuItem::Running => {
+                            if let Some(selected) = running_list_state.selected() {
+                                if selected > 0 {
+                                    running_list_state.select(Some(selected - 1));
+                                    output_window = OUTPUT_TAIL;
                                 }
                             }
-                            MenuItem::Finished => {
-                                if let Some(selected) = finished_list_state.selected() {
-                                    if selected > 0 {
-                                        finished_list_state.select(Some(selected - 1));
-                                    }
+                        }
+                        MenuItem::Finished => {
+                            if let Some(selected) = finished_list_state.selected() {
+                                if selected > 0 {
+                                    finished_list_state.select(Some(selected - 1));
+                                    output_window = OUTPUT_TAIL;
                                 }
                             }
-                            _ => {}
                         }
-                    }
+                        MenuItem::Recurring => {
+                            if let Some(selected) = recurring_list_state.selected() {
+                                if selected > 0 {
+                                    recurring_list_state.select(Some(selected - 1));
+                                }
+                            }
+                        }
+                        _ => {}
+                    },
                     KeyCode::Down | KeyCode::Char('j') => match active_menu_item {
                         MenuItem::Running => {
                             if let Some(selected) = running_list_state.selected() {
                                 let num_command = currently_running.lock().unwrap().len();
-                                if selected < num_command - 1 {
+                                if selected + 1 < num_command {
                                     running_list_state.select(Some(selected + 1));
+                                    output_window = OUTPUT_TAIL;
                                 }
                             }
                         }
                         MenuItem::Finished => {
                             if let Some(selected) = finished_list_state.selected() {
-                                let num_command = tasks.get_finished().lock().unwrap().len();
-                                if selected < num_command - 1 {
+                                let num_command = client.get_finished().lock().unwrap().len();
+                                if selected + 1 < num_command {
                                     finished_list_state.select(Some(selected + 1));
+                                    output_window = OUTPUT_TAIL;
+                                }
+                            }
+                        }
+                        MenuItem::Recurring => {
+                            if let Some(selected) = recurring_list_state.selected() {
+                                let num_command = client.get_recurring().lock().unwrap().len();
+                                if selected + 1 < num_command {
+                                    recurring_list_state.select(Some(selected + 1));
                                 }
                             }
                         }
                         _ => {}
                     },
-                    KeyCode::Char('i') => {
-                        if active_menu_item == MenuItem::Running {
-                            input_mode = InputMode::Command;
+                    KeyCode::Char('t') => follow = !follow,
+                    KeyCode::Char('d') => details = !details,
+                    KeyCode::Char('+') | KeyCode::Char('-')
+                        if active_menu_item == MenuItem::Running =>
+                    {
+                        let selected = selected_id(&running_list_state, currently_running);
+                        // the task may have finished since it was selected
+                        let priority = selected.and_then(|id| {
+                            let running = currently_running.lock().unwrap();
+                            Some((id, running.get(&id)?.spec.priority))
+                        });
+                        if let Some((id, priority)) = priority {
+                            let change = if event.code == KeyCode::Char('+') {
+                                1
+                            } else {
+                                -1
+                            };
+                            client.set_priority(id, priority.saturating_add(change));
+                        }
+                    }
+                    KeyCode::Char('z') if active_menu_item == MenuItem::Running => {
+                        if let Some(id) = selected_id(&running_list_state, currently_running) {
+                            client.toggle_pause(id);
+                        }
+                    }
+                    KeyCode::Char('x') | KeyCode::Delete
+                        if active_menu_item == MenuItem::Running =>
+                    {
+                        if let Some(id) = selected_id(&running_list_state, currently_running) {
+                            client.cancel(id);
+                        }
+                    }
+                    KeyCode::Char('x') | KeyCode::Delete
+                        if active_menu_item == MenuItem::Recurring =>
+                    {
+                        let recurring = client.get_recurring();
+                        let selected = recurring_list_state.selected().and_then(|selected| {
+                            recurring.lock().unwrap().keys().nth(selected).copied()
+                        });
+                        if let Some(id) = selected {
+                            client.remove_recurring(id);
+                        }
+                    }
+                    KeyCode::Char('i') if active_menu_item == MenuItem::Running => {
+                        input_mode = InputMode::Command;
+                    }
+                    KeyCode::Char('a') if active_menu_item == MenuItem::Running => {
+                        if let Some(id) = selected_id(&running_list_state, currently_running) {
+                            // only tasks running under a terminal take input, and
+                            // the task may have finished since it was selected
+                            let running = currently_running.lock().unwrap();
+                            if running.get(&id).is_some_and(|task| task.accepts_input) {
+                                input_mode = InputMode::Attached(id);
+                                follow = true;
+                            }
                         }
                     }
                     _ => {}
                 },
-                InputMode::Command => match event.code {
-                    KeyCode::Char(c) => command_input.insert(command_input.len() - 1, c),
-                    KeyCode::Up => {
-                        if curr_hist_index > 0 {
-                            command_input = command_hist.get(curr_hist_index - 1).unwrap().clone();
-                            command_input.push('_');
-                            curr_hist_index = max(curr_hist_index - 1, 0);
+                InputMode::Attached(_) if event.code == KeyCode::Esc => {
+                    input_mode = InputMode::Normal;
+                }
+                InputMode::Attached(id) => {
+                    if let Some(input) = key_input(event) {
+                        if !client.send_input(id, input) {
+                            input_mode = InputMode::Normal;
                         }
                     }
+                }
+                InputMode::Command => match event.code {
+                    KeyCode::Char(c) => {
+                        cli_error = None;
+                        command_input.insert(command_input.len() - 1, c);
+                    }
+                    KeyCode::Up if curr_hist_index > 0 => {
+                        command_input = command_hist.get(curr_hist_index - 1).unwrap().clone();
+                        command_input.push('_');
+                        curr_hist_index = max(curr_hist_index - 1, 0);
+                    }
                     KeyCode::Down => {
                         curr_hist_index = min(curr_hist_index + 1, command_hist.len());
                         match curr_hist_index.cmp(&command_hist.len()) {
@@ -764,16 +1837,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                     KeyCode::Enter => {
                         command_input.pop();
-                        tasks.push_queue(command_id, command_input.clone());
+                        // reject commands that cannot be run as typed, and keep
+                        // them in the command line so they can be fixed
+                        match submit_input(&command_input, &mut defaults, &client) {
+                            Err(err) => {
+                                cli_error = Some(err);
+                                command_input.push('_');
+                                continue;
+                            }
+                            Ok(Some(mut spec)) => {
+                                // commands run where they were typed, not
+                                // wherever the daemon was started, and with
+                                // the environment they were typed in
+                                if spec.cwd.is_none() {
+                                    spec.cwd = env::current_dir().ok();
+                                }
+                                spec.inherited_env = Some(inherited_env());
+                                if let Err(err) = client.submit(spec) {
+                                    cli_error = Some(err);
+                                    command_input.push('_');
+                                    continue;
+                                }
+                            }
+                            Ok(None) => {}
+                        }
                         command_hist.push(command_input.clone());
                         curr_hist_index = command_hist.len();
-                        command_id += 1;
                         command_input = String::from('_');
                     }
-                    KeyCode::Backspace => {
-                        if command_input.len() > 1 {
-                            command_input.remove(command_input.len() - 2);
-                        }
+                    KeyCode::Backspace if command_input.len() > 1 => {
+                        cli_error = None;
+                        command_input.remove(command_input.len() - 2);
                     }
                     KeyCode::Esc => {
                         input_mode = InputMode::Normal;
@@ -781,7 +1875,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     _ => {}
                 },
             },
-            UIEvent::Tick => {}
+            UIEvent::Tick => {
+                if !client.is_connected() {
+                    break;
+                }
+                // detach once the attached task stopped taking input
+                if let InputMode::Attached(id) = input_mode {
+                    let attached = currently_running
+                        .lock()
+                        .unwrap()
+                        .get(&id)
+                        .is_some_and(|task| task.accepts_input);
+                    if !attached {
+                        input_mode = InputMode::Normal;
+                    }
+                }
+            }
         }
     }
 
@@ -797,9 +1906,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     terminal.clear()?;
     terminal.show_cursor()?;
 
+    if !client.is_connected() {
+        println!("The ctm daemon stopped.");
+    } else if stop {
+        stop_daemon(&client);
+    } else {
+        let workers = client.get_workers();
+        println!(
+            "Detached, {} command(s) keep running. Run ctm again to attach, or ctm --stop to stop them.",
+            workers.busy
+        );
+    }
+
     Ok(())
 }
 
+/// Stops every task of the daemon and then the daemon.
+fn stop_daemon(client: &Client) {
+    let running = client.get_workers().busy;
+    if running > 0 {
+        println!("Stopping {running} running command(s)...");
+    }
+    if !client.shutdown() {
+        eprintln!("Some commands could not be stopped.");
+    }
+}
+
+/// The environment of this process, for the tasks submitted from it.
+/// Variables that are not valid UTF-8 cannot be sent to the daemon and are
+/// left out.
+fn inherited_env() -> Vec<(String, String)> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
 fn load_hist(cmd_hist: &mut Vec<String>) {
     if let Ok(mut file_path) = get_project_root() {
         file_path.push(".cmd_hist");
@@ -813,7 +1954,12 @@ fn load_hist(cmd_hist: &mut Vec<String>) {
 fn save_hist(cmd_hist: Vec<String>) {
     if let Ok(mut file_path) = get_project_root() {
         file_path.push(".cmd_hist");
-        if let Ok(mut f) = OpenOptions::new().create(true).write(true).open(file_path) {
+        if let Ok(mut f) = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file_path)
+        {
             for cmd in cmd_hist {
                 let _ = f.write(format!("{cmd}\n").as_ref());
             }
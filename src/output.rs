@@ -0,0 +1,540 @@
+use crate::format_size;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+/// Bytes of each output stream kept in memory when it is also written to
+/// a log file, and so also how much of it is shown at first.
+pub const OUTPUT_TAIL: usize = 64 * 1024;
+
+/// What happens to the output of a task beyond its cap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CapPolicy {
+    /// Keep the first bytes and discard the rest.
+    Head,
+    /// Keep the last bytes, discarding older ones as new ones come in.
+    Tail,
+    /// Keep the first bytes and stop the task.
+    Kill,
+}
+
+/// How many bytes of each of a task's output streams are kept. The limit
+/// applies to stdout and stderr separately, so a task may keep up to twice
+/// as much in total.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamCap {
+    pub limit: u64,
+    pub policy: CapPolicy,
+}
+
+impl fmt::Display for StreamCap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let policy = match self.policy {
+            CapPolicy::Head => "head",
+            CapPolicy::Tail => "tail",
+            CapPolicy::Kill => "kill",
+        };
+        write!(f, "{policy}:{}", format_size(self.limit))
+    }
+}
+
+/// Output of a task, filled in as the process writes it.
+#[derive(Clone, Default)]
+pub struct TaskOutput {
+    pub stdout: OutputStream,
+    pub stderr: OutputStream,
+}
+
+impl TaskOutput {
+    /// Output to be written to the given stdout and stderr log files, if
+    /// any, and capped at `cap`.
+    pub fn new(logs: Option<(PathBuf, PathBuf)>, cap: Option<StreamCap>) -> Self {
+        let (stdout, stderr) = match logs {
+            Some((stdout, stderr)) => (Some(stdout), Some(stderr)),
+            None => (None, None),
+        };
+        TaskOutput {
+            stdout: OutputStream {
+                log: stdout,
+                cap,
+                ..OutputStream::default()
+            },
+            stderr: OutputStream {
+                log: stderr,
+                cap,
+                ..OutputStream::default()
+            },
+        }
+    }
+
+    /// Whether the task wrote more than its cap allows and should be
+    /// stopped for it.
+    pub fn exceeded(&self) -> bool {
+        self.stdout.exceeded() || self.stderr.exceeded()
+    }
+
+    pub(crate) fn position(&self) -> [Position; 2] {
+        [self.stdout.position(), self.stderr.position()]
+    }
+
+    /// What changed in the output since it was at `sent`, see
+    /// `OutputStream::update`.
+    pub(crate) fn update(&self, sent: Option<[Position; 2]>) -> Option<OutputUpdate> {
+        let stdout = self.stdout.update(sent.map(|sent| sent[0]));
+        let stderr = self.stderr.update(sent.map(|sent| sent[1]));
+        (stdout.is_some() || stderr.is_some()).then_some(OutputUpdate { stdout, stderr })
+    }
+
+    pub(crate) fn apply(&mut self, update: OutputUpdate) {
+        if let Some(stdout) = update.stdout {
+            self.stdout.apply(stdout);
+        }
+        if let Some(stderr) = update.stderr {
+            self.stderr.apply(stderr);
+        }
+    }
+}
+
+/// How far a stream got, as of some update of it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Position {
+    len: u64,
+    discarded_front: u64,
+    discarded_back: u64,
+}
+
+impl Position {
+    /// Offset of the next byte to be kept, counting every byte kept so far
+    /// whether it is still around or not.
+    fn end(&self) -> u64 {
+        self.len - self.discarded_back
+    }
+}
+
+/// Changes to the streams of a `TaskOutput`, `None` for those that did not
+/// change.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct OutputUpdate {
+    stdout: Option<StreamUpdate>,
+    stderr: Option<StreamUpdate>,
+}
+
+/// Changes to an `OutputStream`, which turn a copy of it as of an earlier
+/// update into a copy of it as it is now.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StreamUpdate {
+    len: u64,
+    discarded_front: u64,
+    discarded_back: u64,
+    log: Option<PathBuf>,
+    cap: Option<StreamCap>,
+    /// Whether `bytes` replace what is in memory rather than follow it.
+    restart: bool,
+    #[serde(with = "crate::daemon::base64_bytes")]
+    bytes: Vec<u8>,
+    /// Bytes in memory, what is in front of them is dropped.
+    tail_len: usize,
+}
+
+/// One stream of a task's output. Without a log file all of it is kept in
+/// memory. With one, everything goes to the file and only the latest
+/// `OUTPUT_TAIL` bytes or so are kept in memory; older output is read back
+/// from the file when asked for. Either way output beyond the cap, if
+/// there is one, is discarded.
+#[derive(Clone, Default)]
+pub struct OutputStream {
+    tail: Vec<u8>,
+    /// Bytes the process wrote in total.
+    len: u64,
+    /// Bytes discarded from the start and from the end of the output,
+    /// neither of which is in memory or in the log file.
+    discarded_front: u64,
+    discarded_back: u64,
+    log: Option<PathBuf>,
+    cap: Option<StreamCap>,
+}
+
+impl OutputStream {
+    /// Reads back a stream written to a log file earlier, `None` if there
+    /// is no such file.
+    pub fn from_log(log: PathBuf) -> Option<Self> {
+        let len = log.metadata().ok()?.len();
+        let tail = read_range(&log, len, OUTPUT_TAIL)?;
+        Some(OutputStream {
+            tail,
+            len,
+            log: Some(log),
+            ..OutputStream::default()
+        })
+    }
+
+    /// How many of `n` bytes written next are kept rather than discarded.
+    fn room(&self, n: usize) -> usize {
+        match self.cap {
+            Some(cap) if cap.policy != CapPolicy::Tail => {
+                let room = cap.limit.saturating_sub(self.stored());
+                n.min(room.try_into().unwrap_or(usize::MAX))
+            }
+            _ => n,
+        }
+    }
+
+    /// Takes bytes the process wrote, of which the first `kept` were
+    /// written to the log file already, if any, and the rest discarded.
+    fn push(&mut self, bytes: &[u8], kept: usize) {
+        self.len += bytes.len() as u64;
+        self.discarded_back += (bytes.len() - kept) as u64;
+        self.tail.extend_from_slice(&bytes[..kept]);
+        // trimming only once twice as much as needed has piled up keeps the
+        // copying down to a byte per byte written
+        let tail_cap = self
+            .cap
+            .filter(|cap| cap.policy == CapPolicy::Tail)
+            .map(|cap| usize::try_from(cap.limit).unwrap_or(usize::MAX));
+        let keep = match (&self.log, tail_cap) {
+            (Some(_), Some(limit)) => OUTPUT_TAIL.min(limit),
+            (Some(_), None) => OUTPUT_TAIL,
+            (None, Some(limit)) => limit,
+            (None, None) => return,
+        };
+        if self.tail.len() > keep.saturating_mul(2) {
+            let trimmed = self.tail.len() - keep;
+            self.tail.drain(..trimmed);
+            if self.log.is_none() {
+                self.discarded_front += trimmed as u64;
+            }
+        }
+    }
+
+    /// Discards the first `n` bytes kept, along with those of them still
+    /// in memory.
+    fn discard_front(&mut self, n: u64) {
+        self.discarded_front += n;
+        let stored = usize::try_from(self.stored()).unwrap_or(usize::MAX);
+        let excess = self.tail.len().saturating_sub(stored);
+        self.tail.drain(..excess);
+    }
+
+    /// Stops writing to the log file, keeping further output in memory
+    /// instead. What was only in the file counts as discarded from then on.
+    fn drop_log(&mut self) {
+        self.discarded_front += self.stored() - self.tail.len() as u64;
+        self.log = None;
+    }
+
+    /// Bytes the process wrote in total, discarded ones included.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes kept, in memory or in the log file.
+    fn stored(&self) -> u64 {
+        self.len - self.discarded_front - self.discarded_back
+    }
+
+    pub fn discarded_front(&self) -> u64 {
+        self.discarded_front
+    }
+
+    pub fn discarded_back(&self) -> u64 {
+        self.discarded_back
+    }
+
+    pub fn cap(&self) -> Option<StreamCap> {
+        self.cap
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.cap
+            .is_some_and(|cap| cap.policy == CapPolicy::Kill && self.discarded_back > 0)
+    }
+
+    pub fn log(&self) -> Option<&Path> {
+        self.log.as_deref()
+    }
+
+    pub(crate) fn position(&self) -> Position {
+        Position {
+            len: self.len,
+            discarded_front: self.discarded_front,
+            discarded_back: self.discarded_back,
+        }
+    }
+
+    /// What changed since the stream was at `sent`, to be applied to a copy
+    /// of it as of then. Only bytes written since are sent along, unless
+    /// there is no telling where the copy left off, in which case all of
+    /// those in memory are. `None` if nothing changed.
+    ///
+    /// A stream that is started over, as on a new attempt of the task,
+    /// has to be updated from `None`.
+    pub(crate) fn update(&self, sent: Option<Position>) -> Option<StreamUpdate> {
+        let position = self.position();
+        let start = position.end() - self.tail.len() as u64;
+        let from = match sent {
+            Some(sent) if sent == position => return None,
+            Some(sent) if (start..=position.end()).contains(&sent.end()) => Some(sent.end()),
+            _ => None,
+        };
+        Some(StreamUpdate {
+            len: self.len,
+            discarded_front: self.discarded_front,
+            discarded_back: self.discarded_back,
+            log: self.log.clone(),
+            cap: self.cap,
+            restart: from.is_none(),
+            bytes: self.tail[(from.unwrap_or(start) - start) as usize..].to_vec(),
+            tail_len: self.tail.len(),
+        })
+    }
+
+    /// Brings a copy of a stream up to date with an update of it.
+    pub(crate) fn apply(&mut self, update: StreamUpdate) {
+        if update.restart {
+            self.tail.clear();
+        }
+        self.tail.extend_from_slice(&update.bytes);
+        let dropped = self.tail.len().saturating_sub(update.tail_len);
+        self.tail.drain(..dropped);
+        self.len = update.len;
+        self.discarded_front = update.discarded_front;
+        self.discarded_back = update.discarded_back;
+        self.log = update.log;
+        self.cap = update.cap;
+    }
+
+    /// The last `n` bytes kept, or as many of them as can still be had.
+    /// Those no longer in memory are read from the log file.
+    pub fn last(&self, n: usize) -> Cow<'_, [u8]> {
+        let in_memory = &self.tail[self.tail.len().saturating_sub(n)..];
+        if in_memory.len() >= n || in_memory.len() as u64 == self.stored() {
+            return Cow::Borrowed(in_memory);
+        }
+        // the log file holds exactly the bytes kept
+        match self
+            .log
+            .as_deref()
+            .and_then(|log| read_range(log, self.stored(), n))
+        {
+            Some(bytes) => Cow::Owned(bytes),
+            None => Cow::Borrowed(in_memory),
+        }
+    }
+}
+
+/// Reads the `n` bytes in front of offset `end` of a file, fewer if the
+/// file starts before that.
+fn read_range(path: &Path, end: u64, n: usize) -> Option<Vec<u8>> {
+    let start = end.saturating_sub(n as u64);
+    let mut file = File::open(path).ok()?;
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut bytes = vec![];
+    file.take(end - start).read_to_end(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Copies everything read from `reader` into one of the streams of `output`
+/// until the process closes it, and into the stream's log file if it has
+/// one. Output that cannot be written to the log is kept in memory. The
+/// process is never kept waiting, output beyond the cap is read and
+/// discarded.
+pub fn capture<R: Read + Send + 'static>(
+    mut reader: R,
+    output: Arc<Mutex<TaskOutput>>,
+    stream: fn(&mut TaskOutput) -> &mut OutputStream,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let (path, cap) = {
+            let mut output = output.lock().unwrap();
+            let stream = stream(&mut output);
+            (stream.log.clone(), stream.cap)
+        };
+        let mut log = path.as_ref().and_then(|path| create_private(path).ok());
+        if path.is_some() && log.is_none() {
+            stream(&mut output.lock().unwrap()).drop_log();
+        }
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let kept = stream(&mut output.lock().unwrap()).room(n);
+            let written = log.as_mut().map(|log| log.write_all(&buf[..kept]));
+            let mut output = output.lock().unwrap();
+            let stream = stream(&mut output);
+            if let Some(Err(_)) = written {
+                stream.drop_log();
+                log = None;
+            }
+            stream.push(&buf[..n], kept);
+
+            // a log file capped at its tail is cut down to the cap whenever
+            // it grew to twice that
+            let (Some(path), Some(cap)) = (&path, cap) else {
+                continue;
+            };
+            if log.is_none() || cap.policy != CapPolicy::Tail || stream.stored() <= 2 * cap.limit {
+                continue;
+            }
+            let stored = stream.stored();
+            log = match cut_log(path, stored, cap.limit) {
+                Some(cut) => {
+                    stream.discard_front(stored - cap.limit);
+                    Some(cut)
+                }
+                None => {
+                    stream.drop_log();
+                    None
+                }
+            };
+        }
+    })
+}
+
+/// Cuts a log file of `len` bytes down to its last `limit` bytes, returning
+/// it opened for appending. The cut file is written next to it and then
+/// moved over it, so that it is complete for anyone reading it.
+fn cut_log(path: &Path, len: u64, limit: u64) -> Option<File> {
+    let start = len.saturating_sub(limit);
+    let mut log = File::open(path).ok()?;
+    log.seek(SeekFrom::Start(start)).ok()?;
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    // copied a buffer at a time, the part kept is as large as the cap
+    io::copy(
+        &mut log.take(len - start),
+        &mut create_private(Path::new(&temp_path)).ok()?,
+    )
+    .ok()?;
+    fs::rename(&temp_path, path).ok()?;
+    OpenOptions::new().append(true).open(path).ok()
+}
+
+/// Creates the file at `path`, or empties it, readable only by its owner:
+/// what tasks print and what they were run with may not be for everyone.
+pub(crate) fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, io::Cursor, process};
+
+    const WRITTEN: usize = 300_000;
+
+    /// What a process writes, every byte telling where it is.
+    fn written() -> Vec<u8> {
+        (0..WRITTEN).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn cap(policy: CapPolicy) -> Option<StreamCap> {
+        Some(StreamCap {
+            limit: 1024,
+            policy,
+        })
+    }
+
+    /// Captures everything `written` returns into a stream capped at `cap`,
+    /// and written to a log file in a directory of its own named after
+    /// `test` if `logged`.
+    fn capture_written(test: &str, logged: bool, cap: Option<StreamCap>) -> OutputStream {
+        let logs = logged.then(|| {
+            let dir = env::temp_dir().join(format!("ctm-output-{test}-{}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            (dir.join("stdout"), dir.join("stderr"))
+        });
+        let output = Arc::new(Mutex::new(TaskOutput::new(logs, cap)));
+        capture(Cursor::new(written()), output.clone(), |output| {
+            &mut output.stdout
+        })
+        .join()
+        .unwrap();
+        let stream = output.lock().unwrap().stdout.clone();
+        stream
+    }
+
+    /// Checks what is kept of a stream against what was written, and that
+    /// the log file holds exactly the bytes kept.
+    fn assert_kept(stream: &OutputStream, kept: std::ops::Range<usize>) {
+        let written = written();
+        assert_eq!(stream.len(), WRITTEN as u64);
+        assert_eq!(stream.discarded_front(), kept.start as u64);
+        assert_eq!(stream.discarded_back(), (WRITTEN - kept.end) as u64);
+        assert!(stream.tail.len() as u64 <= stream.stored());
+        assert_eq!(&*stream.last(WRITTEN), &written[kept.clone()]);
+        if let Some(log) = stream.log() {
+            assert_eq!(fs::read(log).unwrap(), &written[kept]);
+        }
+    }
+
+    #[test]
+    fn keeps_everything_without_a_cap() {
+        let stream = capture_written("uncapped", false, None);
+        assert_kept(&stream, 0..WRITTEN);
+        assert_eq!(stream.tail.len(), WRITTEN);
+
+        let stream = capture_written("uncapped-logged", true, None);
+        assert_kept(&stream, 0..WRITTEN);
+        assert!(stream.tail.len() <= 2 * OUTPUT_TAIL);
+    }
+
+    #[test]
+    fn head_cap_keeps_the_first_bytes() {
+        for logged in [false, true] {
+            let stream = capture_written(&format!("head-{logged}"), logged, cap(CapPolicy::Head));
+            assert_kept(&stream, 0..1024);
+            assert!(!stream.exceeded());
+        }
+    }
+
+    #[test]
+    fn kill_cap_keeps_the_first_bytes_and_asks_to_stop() {
+        for logged in [false, true] {
+            let stream = capture_written(&format!("kill-{logged}"), logged, cap(CapPolicy::Kill));
+            assert_kept(&stream, 0..1024);
+            assert!(stream.exceeded());
+        }
+    }
+
+    #[test]
+    fn tail_cap_keeps_the_last_bytes() {
+        for logged in [false, true] {
+            let stream = capture_written(&format!("tail-{logged}"), logged, cap(CapPolicy::Tail));
+            // both memory and the log are cut down to the cap only once
+            // twice as much piled up
+            let kept = stream.stored() as usize;
+            assert!((1024..=2048).contains(&kept), "{kept}");
+            assert_kept(&stream, WRITTEN - kept..WRITTEN);
+            assert!(!stream.exceeded());
+        }
+    }
+
+    #[test]
+    fn dropping_the_log_keeps_what_is_in_memory() {
+        let mut stream = capture_written("tail-dropped", true, cap(CapPolicy::Tail));
+        let in_memory = stream.tail.len();
+        stream.drop_log();
+        assert_eq!(stream.stored(), in_memory as u64);
+        assert_kept(&stream, WRITTEN - in_memory..WRITTEN);
+    }
+}
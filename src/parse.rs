@@ -0,0 +1,421 @@
+use serde::{Deserialize, Serialize};
+use std::{env, fmt, time::Duration};
+
+/// Reasons a line typed into the command line could not be turned into a task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParseError {
+    Empty,
+    UnterminatedSingleQuote(usize),
+    UnterminatedDoubleQuote(usize),
+    UnterminatedBrace(usize),
+    BadSubstitution(usize),
+    TrailingBackslash,
+    UnknownDirective(String),
+    MissingArgument(String),
+    /// A directive was given an argument it does not understand.
+    InvalidArgument(String, String),
+    /// A directive that only applies to a single task was given alone.
+    MissingCommand,
+    /// A directive that changes global settings was given with a command.
+    UnexpectedCommand,
+    /// A directive refers to a task id that was never submitted.
+    UnknownTask(usize),
+    /// The daemon the command would have been sent to is gone.
+    Disconnected,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::UnterminatedSingleQuote(col) => {
+                write!(f, "unterminated single quote starting at column {col}")
+            }
+            ParseError::UnterminatedDoubleQuote(col) => {
+                write!(f, "unterminated double quote starting at column {col}")
+            }
+            ParseError::UnterminatedBrace(col) => {
+                write!(f, "missing '}}' for '${{' at column {col}")
+            }
+            ParseError::BadSubstitution(col) => write!(f, "bad substitution at column {col}"),
+            ParseError::TrailingBackslash => write!(f, "trailing backslash"),
+            ParseError::UnknownDirective(name) => write!(f, "unknown directive ':{name}'"),
+            ParseError::MissingArgument(name) => write!(f, "':{name}' needs an argument"),
+            ParseError::InvalidArgument(name, arg) => {
+                write!(f, "invalid argument '{arg}' for ':{name}'")
+            }
+            ParseError::MissingCommand => write!(f, "directive needs a command to apply to"),
+            ParseError::UnexpectedCommand => {
+                write!(f, "directive cannot be combined with a command")
+            }
+            ParseError::UnknownTask(id) => write!(f, "there is no task #{id}"),
+            ParseError::Disconnected => write!(f, "not connected to the ctm daemon"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Splits a command line into words the way a POSIX shell would:
+/// - unquoted whitespace separates words, runs of it count as one separator
+/// - '...' keeps everything literally
+/// - "..." keeps whitespace but still expands variables, and `\` only
+///   escapes `$`, `` ` ``, `"`, `\` and newlines
+/// - an unquoted `\` escapes the character that follows it
+/// - `$VAR` and `${VAR}` expand from ctm's environment (unset means empty)
+/// - a leading unquoted `~` or `~/` expands to `$HOME`
+///
+/// Unlike a shell, expanded values are never split again, so `$FLAGS`
+/// always ends up as a single argument.
+pub fn split_command(line: &str) -> Result<Vec<String>, ParseError> {
+    split_command_with(line, &|name| env::var(name).ok())
+}
+
+/// Splits a command line like `split_command`, looking variables up with
+/// `var` instead of in ctm's environment.
+pub fn split_command_with(
+    line: &str,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<String>, ParseError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut words = vec![];
+    let mut word = String::new();
+    // distinguishes `""` (an empty argument) from no argument at all
+    let mut in_word = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+                i += 1;
+            }
+            '\'' => {
+                let start = i;
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(ParseError::UnterminatedSingleQuote(start + 1)),
+                        Some('\'') => break,
+                        Some(c) => word.push(*c),
+                    }
+                    i += 1;
+                }
+                in_word = true;
+                i += 1;
+            }
+            '"' => {
+                let start = i;
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(ParseError::UnterminatedDoubleQuote(start + 1)),
+                        Some('"') => break,
+                        Some('\\') => match chars.get(i + 1) {
+                            Some('\n') => i += 2,
+                            Some(next @ ('$' | '`' | '"' | '\\')) => {
+                                word.push(*next);
+                                i += 2;
+                            }
+                            _ => {
+                                word.push('\\');
+                                i += 1;
+                            }
+                        },
+                        Some('$') => i = expand_variable(&chars, i, &mut word, var)?,
+                        Some(c) => {
+                            word.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                in_word = true;
+                i += 1;
+            }
+            '\\' => match chars.get(i + 1) {
+                None => return Err(ParseError::TrailingBackslash),
+                // an escaped newline is a line continuation
+                Some('\n') => i += 2,
+                Some(next) => {
+                    word.push(*next);
+                    in_word = true;
+                    i += 2;
+                }
+            },
+            '$' => {
+                i = expand_variable(&chars, i, &mut word, var)?;
+                in_word = true;
+            }
+            '~' if !in_word && matches!(chars.get(i + 1), None | Some(' ' | '\t' | '\n' | '/')) => {
+                word.push_str(&var("HOME").unwrap_or_else(|| "~".to_string()));
+                in_word = true;
+                i += 1;
+            }
+            _ => {
+                word.push(c);
+                in_word = true;
+                i += 1;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    if words.is_empty() {
+        Err(ParseError::Empty)
+    } else {
+        Ok(words)
+    }
+}
+
+/// Splits the first word off `line` by the quoting rules of
+/// `split_command`, returning it as typed, quotes and all, and the rest of
+/// the line. An unterminated quote makes the rest of the line part of the
+/// word, for `split_command` to report.
+pub(crate) fn next_word(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => return (&line[..i], line[i..].trim_start()),
+            '\'' => {
+                chars.find(|(_, c)| *c == '\'');
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            '\\' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+/// Expands the variable reference starting at the `$` in `chars[start]`
+/// into `word`, returning the index right after the reference.
+/// A `$` that does not start a name is kept as is.
+fn expand_variable(
+    chars: &[char],
+    start: usize,
+    word: &mut String,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<usize, ParseError> {
+    let is_name_char = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+
+    match chars.get(start + 1) {
+        Some('{') => {
+            let name_start = start + 2;
+            let name_len = chars[name_start..]
+                .iter()
+                .position(|c| *c == '}')
+                .ok_or(ParseError::UnterminatedBrace(start + 1))?;
+            let name: String = chars[name_start..name_start + name_len].iter().collect();
+            if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+                return Err(ParseError::BadSubstitution(start + 1));
+            }
+            if !name.chars().all(|c| is_name_char(&c)) {
+                return Err(ParseError::BadSubstitution(start + 1));
+            }
+            word.push_str(&var(&name).unwrap_or_default());
+            Ok(name_start + name_len + 1)
+        }
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+            let name_len = chars[start + 1..]
+                .iter()
+                .position(|c| !is_name_char(c))
+                .unwrap_or(chars.len() - start - 1);
+            let name: String = chars[start + 1..start + 1 + name_len].iter().collect();
+            word.push_str(&var(&name).unwrap_or_default());
+            Ok(start + 1 + name_len)
+        }
+        _ => {
+            word.push('$');
+            Ok(start + 1)
+        }
+    }
+}
+
+/// Parses a duration such as `90`, `500ms`, `30s`, `10m`, `1h30m` or `2d`.
+/// A bare number is taken as seconds.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    if let Ok(secs) = text.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number: u32 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+        total = total.checked_add(unit.checked_mul(number)?)?;
+    }
+    (!text.is_empty()).then_some(total)
+}
+
+/// Formats a duration the way `parse_duration` reads it, e.g. `1h2m3s`.
+/// Durations of a second or more are cut to whole seconds.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h{minutes}m{secs}s")
+    } else if minutes > 0 {
+        format!("{minutes}m{secs}s")
+    } else if secs > 0 || duration.is_zero() {
+        format!("{secs}s")
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Vec<String> {
+        split_command(line).unwrap()
+    }
+
+    #[test]
+    fn splits_on_runs_of_whitespace() {
+        assert_eq!(split("  ls \t -l\n  /tmp "), ["ls", "-l", "/tmp"]);
+    }
+
+    #[test]
+    fn single_quotes_keep_everything() {
+        assert_eq!(
+            split(r#"echo 'a  b' '$HOME' '\n' '"'"#),
+            ["echo", "a  b", "$HOME", r"\n", "\""]
+        );
+    }
+
+    #[test]
+    fn double_quotes_escape_only_some_characters() {
+        assert_eq!(
+            split(
+                r#"echo "a  b" "\$x \" \\ \n" "a\
+b""#
+            ),
+            ["echo", "a  b", r#"$x " \ \n"#, "ab"]
+        );
+    }
+
+    #[test]
+    fn empty_quotes_are_an_argument() {
+        assert_eq!(split(r#"printf '' """#), ["printf", "", ""]);
+        assert_eq!(split(r#"a""b'c'd"#), ["abcd"]);
+    }
+
+    #[test]
+    fn backslash_escapes_outside_quotes() {
+        assert_eq!(split(r"touch a\ b \'x \$y"), ["touch", "a b", "'x", "$y"]);
+        assert_eq!(split("ls \\\n-l"), ["ls", "-l"]);
+    }
+
+    #[test]
+    fn expands_variables_without_splitting_them() {
+        env::set_var("CTM_TEST_SPLIT", "one two");
+        env::remove_var("CTM_TEST_UNSET");
+        assert_eq!(
+            split(r#"echo $CTM_TEST_SPLIT "${CTM_TEST_SPLIT}!" x$CTM_TEST_UNSET-y"#),
+            ["echo", "one two", "one two!", "x-y"]
+        );
+        assert_eq!(split("echo $ $1 a$"), ["echo", "$", "$1", "a$"]);
+    }
+
+    #[test]
+    fn expands_from_the_environment_given() {
+        let var = |name: &str| match name {
+            "HOME" => Some("/home/client".to_string()),
+            "A" => Some("a b".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            split_command_with("ls ~/x $A ${A}c $PATH", &var).unwrap(),
+            ["ls", "/home/client/x", "a b", "a bc", ""]
+        );
+        assert_eq!(split_command_with("ls ~", &|_| None).unwrap(), ["ls", "~"]);
+    }
+
+    #[test]
+    fn expands_leading_tilde_only() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(
+            split("ls ~ ~/src a~ ~user"),
+            ["ls", &home, &format!("{home}/src"), "a~", "~user"]
+        );
+        assert_eq!(split("ls '~' \\~"), ["ls", "~", "~"]);
+    }
+
+    #[test]
+    fn formatted_durations_parse_back() {
+        for secs in [0, 1, 59, 60, 90, 3599, 3600, 3723, 90000] {
+            let duration = Duration::from_secs(secs);
+            assert_eq!(parse_duration(&format_duration(duration)), Some(duration));
+        }
+        let duration = Duration::from_millis(250);
+        assert_eq!(format_duration(duration), "250ms");
+        assert_eq!(parse_duration(&format_duration(duration)), Some(duration));
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h2m3s");
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        assert_eq!(
+            split_command("echo 'abc"),
+            Err(ParseError::UnterminatedSingleQuote(6))
+        );
+        assert_eq!(
+            split_command("a \"b"),
+            Err(ParseError::UnterminatedDoubleQuote(3))
+        );
+        assert_eq!(
+            split_command("echo ${HOME"),
+            Err(ParseError::UnterminatedBrace(6))
+        );
+        assert_eq!(
+            split_command("echo ${}"),
+            Err(ParseError::BadSubstitution(6))
+        );
+        assert_eq!(
+            split_command("echo ${1x}"),
+            Err(ParseError::BadSubstitution(6))
+        );
+        assert_eq!(
+            split_command("echo ${A-B}"),
+            Err(ParseError::BadSubstitution(6))
+        );
+        assert_eq!(split_command("echo \\"), Err(ParseError::TrailingBackslash));
+        assert_eq!(split_command("   "), Err(ParseError::Empty));
+    }
+}
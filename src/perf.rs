@@ -30,7 +30,7 @@ impl<T> PerfLog<T> {
         self.data.back()
     }
 
-    pub fn iter(&self) -> PerfLogIter<T> {
+    pub fn iter(&self) -> PerfLogIter<'_, T> {
         PerfLogIter {
             data: &self.data,
             index: 0,
@@ -0,0 +1,79 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    process::{Command, Stdio},
+    ptr,
+    sync::mpsc::{channel, Sender},
+    thread,
+};
+
+/// Size of the terminal that tasks running under a pseudo-terminal see.
+const PTY_ROWS: u16 = 24;
+const PTY_COLUMNS: u16 = 80;
+
+/// Sets `command` up to run with a new pseudo-terminal as its controlling
+/// terminal and its stdin, stdout and stderr. Returns the master side,
+/// which reads what the command prints and writes what it reads.
+///
+/// The command becomes the leader of a session of its own, and so also of
+/// a process group with the id of its pid. `command` holds on to the
+/// terminal side until it is dropped, which has to happen right after
+/// spawning so that reading from the master ends once the process exits.
+pub fn attach(command: &mut Command) -> io::Result<File> {
+    let mut master = -1;
+    let mut slave = -1;
+    let size = libc::winsize {
+        ws_row: PTY_ROWS,
+        ws_col: PTY_COLUMNS,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: openpty only writes the two descriptors, and does not keep
+    // the pointers beyond the call
+    let opened =
+        unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &size) };
+    if opened == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just opened and nothing else owns them
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    // neither end should leak into processes started later on
+    for fd in [&master, &slave] {
+        // SAFETY: setting a descriptor flag on a descriptor we own
+        unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    command
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    // SAFETY: setsid and ioctl are async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(File::from(master))
+}
+
+/// Writes whatever is sent through the returned channel to the terminal,
+/// on a thread of its own so a command that does not read its input
+/// cannot block the sender. The thread exits once the sender is dropped.
+pub fn forward_input(mut master: File) -> Sender<Vec<u8>> {
+    let (sender, receiver) = channel::<Vec<u8>>();
+    thread::spawn(move || {
+        for input in receiver {
+            if master.write_all(&input).is_err() {
+                break;
+            }
+        }
+    });
+    sender
+}
@@ -0,0 +1,368 @@
+use crate::{format_duration, TaskSpec};
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
+
+/// How far ahead to look for the next time a cron expression matches,
+/// enough for `0 0 29 2 *` to still find a leap year.
+const CRON_SEARCH_DAYS: u32 = 366 * 8;
+
+/// A cron expression: minute, hour, day of month, month and day of week.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cron {
+    expression: String,
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days: Vec<u32>,
+    months: Vec<u32>,
+    /// 0 is Sunday.
+    weekdays: Vec<u32>,
+    /// Whether the day of month or of week was restricted. When both are,
+    /// either matching is enough, as in every other cron.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Cron {
+    /// Parses the five fields of a cron expression. Each field is `*`, a
+    /// number, a range such as `1-5`, a step such as `*/15` or `0-30/10`,
+    /// or a comma separated list of those.
+    pub fn parse(fields: &[&str]) -> Option<Cron> {
+        let [minutes, hours, days, months, weekdays] = fields else {
+            return None;
+        };
+        let mut weekday_values = parse_field(weekdays, 0, 7)?;
+        // both 0 and 7 are Sunday
+        for weekday in &mut weekday_values {
+            *weekday %= 7;
+        }
+        weekday_values.sort_unstable();
+        weekday_values.dedup();
+        Some(Cron {
+            expression: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_values,
+            days_restricted: *days != "*",
+            weekdays_restricted: *weekdays != "*",
+        })
+    }
+
+    fn matches_day(&self, date: chrono::NaiveDate) -> bool {
+        if !self.months.contains(&date.month()) {
+            return false;
+        }
+        let day = self.days.contains(&date.day());
+        let weekday = self
+            .weekdays
+            .contains(&date.weekday().num_days_from_sunday());
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time after `after` the expression matches.
+    fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut date = after.date_naive();
+        for _ in 0..CRON_SEARCH_DAYS {
+            if self.matches_day(date) {
+                for hour in &self.hours {
+                    for minute in &self.minutes {
+                        let time = date.and_hms_opt(*hour, *minute, 0)?;
+                        // times skipped by a DST change never come
+                        let Some(time) = Local.from_local_datetime(&time).earliest() else {
+                            continue;
+                        };
+                        if time > after {
+                            return Some(time);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Option<Vec<u32>> {
+    let mut values = vec![];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                // `5/15` means from 5 onwards
+                None if part.contains('/') => (range.parse().ok()?, max),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, value)
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        values.extend((start..=end).step_by(step));
+    }
+    values.sort_unstable();
+    values.dedup();
+    Some(values)
+}
+
+/// When a recurring task runs.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Recurrence {
+    /// Right away, and then every time the interval has passed.
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Recurrence {
+    /// The first run of a task set up at `now`.
+    pub fn first(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Recurrence::Every(_) => Some(now),
+            Recurrence::Cron(cron) => cron.next_after(now),
+        }
+    }
+
+    /// The run following one that was due at `previous`. Runs missed in
+    /// the meantime, say because the machine was asleep, are not made up.
+    pub fn next(&self, previous: DateTime<Local>, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Recurrence::Every(interval) => {
+                let interval = ChronoDuration::from_std(*interval).ok()?;
+                let mut next = previous + interval;
+                if next <= now {
+                    let missed = (now - next).num_milliseconds() / interval.num_milliseconds();
+                    next += interval * (missed as i32 + 1);
+                }
+                Some(next)
+            }
+            Recurrence::Cron(cron) => cron.next_after(now.max(previous)),
+        }
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recurrence::Every(interval) => write!(f, "every {}", format_duration(*interval)),
+            Recurrence::Cron(cron) => write!(f, "cron {}", cron.expression),
+        }
+    }
+}
+
+/// What to do when a recurring task is due while its previous run is
+/// still going.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Overlap {
+    /// Leave this run out.
+    #[default]
+    Skip,
+    /// Start this run once the previous one is done. Only one run is ever
+    /// held back like that.
+    Queue,
+    /// Start this run anyway.
+    Allow,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let res = match self {
+            Overlap::Skip => "skip",
+            Overlap::Queue => "queue",
+            Overlap::Allow => "allow",
+        };
+        write!(f, "{res}")
+    }
+}
+
+/// A task definition that creates a new task every time it is due.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Recurring {
+    /// Spec of the runs. Its `recurrence` is what makes them recur.
+    pub spec: TaskSpec,
+    /// When the next run is due, `None` once a cron expression never
+    /// matches again.
+    pub next_run: Option<DateTime<Local>>,
+    /// Ids of the tasks created so far, oldest first.
+    pub runs: Vec<usize>,
+    /// Runs left out because the previous one was still going.
+    pub skipped: u32,
+    /// Set while a run is held back until the previous one is done.
+    pub pending: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use std::env;
+
+    /// Central European time, with the switches to and from summer time of
+    /// 2023 on March 26 and October 29.
+    const TIME_ZONE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    /// Local time, in the time zone every test here uses.
+    fn local(time: &str) -> DateTime<Local> {
+        env::set_var("TZ", TIME_ZONE);
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&time).earliest().unwrap()
+    }
+
+    fn cron(expression: &str) -> Cron {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        Cron::parse(&fields).unwrap()
+    }
+
+    #[test]
+    fn parses_steps_ranges_and_lists() {
+        let cron = cron("*/15 0-20/10 5/10 1,3-4 */2");
+        assert_eq!(cron.minutes, [0, 15, 30, 45]);
+        assert_eq!(cron.hours, [0, 10, 20]);
+        assert_eq!(cron.days, [5, 15, 25]);
+        assert_eq!(cron.months, [1, 3, 4]);
+        assert_eq!(cron.weekdays, [0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn sunday_is_both_0_and_7() {
+        assert_eq!(cron("0 0 * * 7").weekdays, [0]);
+        assert_eq!(cron("0 0 * * 0,7").weekdays, [0]);
+        assert_eq!(cron("0 0 * * 5-7").weekdays, [0, 5, 6]);
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        for expression in [
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "1,,2 * * * *",
+            "* * * *",
+            "* * * * * *",
+        ] {
+            let fields: Vec<&str> = expression.split_whitespace().collect();
+            assert_eq!(Cron::parse(&fields), None, "{expression}");
+        }
+    }
+
+    #[test]
+    fn next_time_is_strictly_after() {
+        let cron = cron("*/15 9-17 * * *");
+        assert_eq!(
+            cron.next_after(local("2023-02-01 09:00")),
+            Some(local("2023-02-01 09:15"))
+        );
+        assert_eq!(
+            cron.next_after(local("2023-02-01 17:45")),
+            Some(local("2023-02-02 09:00"))
+        );
+    }
+
+    #[test]
+    fn day_of_month_or_of_week_is_enough_when_both_are_given() {
+        // February 13, 2023 is a Monday, the Fridays around it are the 10th
+        // and the 17th
+        let either = cron("0 0 13 * 5");
+        assert_eq!(
+            either.next_after(local("2023-02-09 12:00")),
+            Some(local("2023-02-10 00:00"))
+        );
+        assert_eq!(
+            either.next_after(local("2023-02-11 12:00")),
+            Some(local("2023-02-13 00:00"))
+        );
+        assert_eq!(
+            either.next_after(local("2023-02-13 12:00")),
+            Some(local("2023-02-17 00:00"))
+        );
+
+        assert_eq!(
+            cron("0 0 13 * *").next_after(local("2023-02-09 12:00")),
+            Some(local("2023-02-13 00:00"))
+        );
+        assert_eq!(
+            cron("0 0 * * 5").next_after(local("2023-02-11 12:00")),
+            Some(local("2023-02-17 00:00"))
+        );
+        // the month still has to match either way
+        assert_eq!(
+            cron("0 0 13 3 5").next_after(local("2023-02-09 12:00")),
+            Some(local("2023-03-03 00:00"))
+        );
+    }
+
+    #[test]
+    fn finds_february_29_years_ahead() {
+        assert_eq!(
+            cron("0 0 29 2 *").next_after(local("2023-01-01 00:00")),
+            Some(local("2024-02-29 00:00"))
+        );
+    }
+
+    #[test]
+    fn times_skipped_by_dst_never_come() {
+        // clocks go from 02:00 straight to 03:00 on March 26
+        let cron = cron("30 2 * * *");
+        assert_eq!(
+            cron.next_after(local("2023-03-25 12:00")),
+            Some(local("2023-03-27 02:30"))
+        );
+    }
+
+    #[test]
+    fn times_repeated_by_dst_come_once() {
+        // clocks go from 03:00 back to 02:00 on October 29
+        let cron = cron("30 2 * * *");
+        let first = cron.next_after(local("2023-10-29 00:00")).unwrap();
+        assert_eq!(first, local("2023-10-29 02:30"));
+        assert_eq!(cron.next_after(first), Some(local("2023-10-30 02:30")));
+    }
+
+    #[test]
+    fn hourly_runs_continue_across_dst() {
+        let cron = cron("0 * * * *");
+        let before = local("2023-03-26 01:00");
+        let after = cron.next_after(before).unwrap();
+        assert_eq!(after, local("2023-03-26 03:00"));
+        assert_eq!(after - before, ChronoDuration::hours(1));
+    }
+
+    #[test]
+    fn intervals_leave_out_missed_runs() {
+        let every = Recurrence::Every(Duration::from_secs(10 * 60));
+        let previous = local("2023-02-01 09:00");
+        assert_eq!(
+            every.next(previous, local("2023-02-01 09:05")),
+            Some(local("2023-02-01 09:10"))
+        );
+        assert_eq!(
+            every.next(previous, local("2023-02-01 09:35")),
+            Some(local("2023-02-01 09:40"))
+        );
+    }
+
+    #[test]
+    fn cron_runs_missed_are_left_out() {
+        let hourly = Recurrence::Cron(cron("0 * * * *"));
+        assert_eq!(
+            hourly.next(local("2023-02-01 09:00"), local("2023-02-01 12:30")),
+            Some(local("2023-02-01 13:00"))
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{format_duration, CommandStatus};
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
+
+/// Longest we ever wait between two attempts, however many there were.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait before running a failed task again.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Backoff {
+    /// The same delay before every retry.
+    Fixed(Duration),
+    /// The delay doubles with every retry, starting from the given one.
+    Exponential(Duration),
+}
+
+impl Backoff {
+    /// Delay before the attempt following attempt number `attempt` (1-based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential(base) => {
+                let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+                base.checked_mul(factor).unwrap_or(MAX_BACKOFF)
+            }
+        }
+        .min(MAX_BACKOFF)
+    }
+}
+
+impl fmt::Display for Backoff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backoff::Fixed(delay) => write!(f, "fixed:{}", format_duration(*delay)),
+            Backoff::Exponential(base) => write!(f, "exp:{}", format_duration(*base)),
+        }
+    }
+}
+
+/// When and how often a task that did not succeed is run again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// Exit codes worth retrying. When empty, any failure is retried.
+    pub retry_on: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Backoff::Fixed(Duration::from_secs(1)),
+            retry_on: vec![],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether an attempt that ended with `status` should be retried.
+    /// Tasks that were cancelled, could not be started at all or ran into a
+    /// resource limit, which they would just run into again, are not.
+    pub fn should_retry(&self, status: &CommandStatus) -> bool {
+        match status {
+            CommandStatus::Exited(code) => self.retry_on.is_empty() || self.retry_on.contains(code),
+            CommandStatus::Signalled { .. } | CommandStatus::TimedOut(_) => {
+                self.retry_on.is_empty()
+            }
+            _ => false,
+        }
+    }
+}
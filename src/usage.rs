@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, time::Instant};
+
+/// Resources used by a task's processes at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    /// CPU time used since the previous sample, in percent of one core.
+    pub cpu: f64,
+    /// Resident memory, in bytes.
+    pub memory: u64,
+    pub threads: u64,
+    pub processes: usize,
+}
+
+/// What `/proc/<pid>/stat` says about a process.
+struct ProcessStat {
+    /// Single letter state, `Z` for zombies.
+    state: char,
+    parent: u32,
+    group: u32,
+    /// Clock ticks spent running, including those of children it reaped.
+    ticks: u64,
+    /// Resident memory, in pages.
+    rss: u64,
+    threads: u64,
+}
+
+fn read_stat(pid: u32) -> Option<ProcessStat> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the command name is in parentheses and may contain anything, so the
+    // fields are counted from the last closing one
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |n: usize| fields.get(n)?.parse::<u64>().ok();
+    Some(ProcessStat {
+        state: fields.first()?.chars().next()?,
+        parent: field(1)? as u32,
+        group: field(2)? as u32,
+        ticks: field(11)? + field(12)? + field(13)? + field(14)?,
+        threads: field(17)?,
+        rss: field(21)?,
+    })
+}
+
+/// Reads the stat of every process there is, by pid.
+fn all_processes() -> Option<HashMap<u32, ProcessStat>> {
+    let mut processes = HashMap::new();
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+            continue;
+        };
+        // processes may exit while we are looking
+        if let Some(stat) = read_stat(pid) {
+            processes.insert(pid, stat);
+        }
+    }
+    Some(processes)
+}
+
+/// Processes in a process group that have not exited yet.
+pub fn group_members(group: u32) -> Vec<u32> {
+    all_processes()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, stat)| stat.group == group && stat.state != 'Z')
+        .map(|(pid, _)| pid)
+        .collect()
+}
+
+/// Samples the resource usage of a process and all of its descendants.
+pub struct Sampler {
+    pid: u32,
+    previous: Option<(Instant, u64)>,
+}
+
+impl Sampler {
+    pub fn new(pid: u32) -> Self {
+        Sampler {
+            pid,
+            previous: None,
+        }
+    }
+
+    /// Takes a sample, `None` once the process is gone. The CPU usage of the
+    /// first sample is always 0, as there is nothing to compare it with yet.
+    pub fn sample(&mut self) -> Option<Usage> {
+        let processes = all_processes()?;
+        processes.get(&self.pid)?;
+
+        let mut tree = vec![self.pid];
+        let mut i = 0;
+        while i < tree.len() {
+            let parent = tree[i];
+            tree.extend(
+                processes
+                    .iter()
+                    .filter(|(_, stat)| stat.parent == parent)
+                    .map(|(pid, _)| *pid),
+            );
+            i += 1;
+        }
+
+        // SAFETY: sysconf has no preconditions
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+        let mut usage = Usage {
+            processes: tree.len(),
+            ..Usage::default()
+        };
+        let mut ticks = 0;
+        for pid in &tree {
+            let stat = &processes[pid];
+            ticks += stat.ticks;
+            usage.memory += stat.rss * page_size;
+            usage.threads += stat.threads;
+        }
+
+        let now = Instant::now();
+        if let Some((then, previous_ticks)) = self.previous {
+            let elapsed = now.duration_since(then).as_secs_f64();
+            // descendants that exit before being reaped take their ticks
+            // with them, which must not turn into negative usage
+            let used = ticks.saturating_sub(previous_ticks) as f64 / ticks_per_second;
+            if elapsed > 0.0 {
+                usage.cpu = used / elapsed * 100.0;
+            }
+        }
+        self.previous = Some((now, ticks));
+        Some(usage)
+    }
+}